@@ -34,3 +34,11 @@ pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>, frame_allocator: &mut impl
     unsafe { super::ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE); }
     Ok(())
 }
+
+//堆的使用量和剩余量（字节）
+pub fn heap_usage() -> (usize, usize) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let heap = super::ALLOCATOR.lock();
+        (heap.used(), heap.free())
+    })
+}
@@ -0,0 +1,175 @@
+use vga::colors::Color16;
+
+const ESC: u8 = 0x1b;
+const MAX_PARAMS: usize = 8;
+
+//SGR的30～37和40～47，依次为黑、红、绿、黄、蓝、品红、青、白
+const COLORS: [Color16; 8] = [
+    Color16::Black,
+    Color16::Red,
+    Color16::Green,
+    Color16::Brown,
+    Color16::Blue,
+    Color16::Magenta,
+    Color16::Cyan,
+    Color16::LightGrey,
+];
+
+//90～97、100～107以及粗体时使用的明亮的颜色
+const BRIGHT_COLORS: [Color16; 8] = [
+    Color16::DarkGrey,
+    Color16::LightRed,
+    Color16::LightGreen,
+    Color16::Yellow,
+    Color16::LightBlue,
+    Color16::Pink,
+    Color16::LightCyan,
+    Color16::White,
+];
+
+//CSI序列：ESC [ 参数 ; 参数 ... 命令
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    pub params: [u16; MAX_PARAMS],
+    pub len: usize,
+    pub private: bool, //以?开头的序列
+    pub command: u8,
+}
+
+impl Csi {
+    //省略或为0的参数取default
+    pub fn param(&self, i: usize, default: u16) -> u16 {
+        if i < self.len && self.params[i] != 0 {
+            self.params[i]
+        } else {
+            default
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    Print(u8),
+    Control(u8), //\n、\r、\t、退格等控制字符
+    Csi(Csi),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+}
+
+//逐字节解析，序列可以跨越多次写入
+pub struct AnsiParser {
+    state: State,
+    csi: Csi,
+}
+
+impl AnsiParser {
+    pub const fn new() -> AnsiParser {
+        AnsiParser {
+            state: State::Ground,
+            csi: Csi { params: [0; MAX_PARAMS], len: 0, private: false, command: 0 },
+        }
+    }
+
+    pub fn feed(&mut self, byte: u8) -> Option<Action> {
+        match self.state {
+            State::Ground => match byte {
+                ESC => {
+                    self.state = State::Escape;
+                    None
+                }
+                0x00..=0x1f | 0x7f => Some(Action::Control(byte)),
+                _ => Some(Action::Print(byte)),
+            },
+            State::Escape => {
+                //只支持CSI，其他的ESC序列忽略
+                if byte == b'[' {
+                    self.csi = Csi { params: [0; MAX_PARAMS], len: 0, private: false, command: 0 };
+                    self.state = State::Csi;
+                } else {
+                    self.state = State::Ground;
+                }
+                None
+            }
+            State::Csi => match byte {
+                b'0'..=b'9' => {
+                    if self.csi.len == 0 {
+                        self.csi.len = 1;
+                    }
+                    let param = &mut self.csi.params[self.csi.len - 1];
+                    *param = param.saturating_mul(10).saturating_add((byte - b'0') as u16);
+                    None
+                }
+                b';' => {
+                    if self.csi.len == 0 {
+                        self.csi.len = 1;
+                    }
+                    if self.csi.len < MAX_PARAMS {
+                        self.csi.len += 1;
+                    }
+                    None
+                }
+                b'?' => {
+                    self.csi.private = true;
+                    None
+                }
+                0x40..=0x7e => {
+                    self.state = State::Ground;
+                    self.csi.command = byte;
+                    Some(Action::Csi(self.csi))
+                }
+                //序列中的控制字符直接执行
+                0x00..=0x1f => Some(Action::Control(byte)),
+                _ => None,
+            },
+        }
+    }
+}
+
+//文字的颜色属性，bg为None时不填充背景
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attr {
+    fg: Color16,
+    pub bg: Option<Color16>,
+    bold: bool,
+}
+
+impl Attr {
+    pub const fn new(fg: Color16, bg: Option<Color16>) -> Attr {
+        Attr { fg, bg, bold: false }
+    }
+
+    //粗体时使用明亮的颜色
+    pub fn fg(&self) -> Color16 {
+        if self.bold {
+            if let Some(i) = COLORS.iter().position(|&c| c == self.fg) {
+                return BRIGHT_COLORS[i];
+            }
+        }
+        self.fg
+    }
+
+    //按SGR（ESC [ ... m）修改属性，0和39、49恢复为default
+    pub fn apply_sgr(&mut self, csi: &Csi, default: Attr) {
+        let len = csi.len.max(1);
+        for i in 0..len {
+            let code = if i < csi.len { csi.params[i] } else { 0 };
+            match code {
+                0 => *self = default,
+                1 => self.bold = true,
+                22 => self.bold = false,
+                30..=37 => self.fg = COLORS[(code - 30) as usize],
+                39 => self.fg = default.fg,
+                40..=47 => self.bg = Some(COLORS[(code - 40) as usize]),
+                49 => self.bg = default.bg,
+                90..=97 => self.fg = BRIGHT_COLORS[(code - 90) as usize],
+                100..=107 => self.bg = Some(BRIGHT_COLORS[(code - 100) as usize]),
+                _ => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,166 @@
+use core::arch::x86_64::__cpuid;
+use core::ptr::{read_volatile, write_volatile};
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::{PhysAddr, VirtAddr};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB};
+use crate::asm::{io_cli, io_hlt, io_in8, io_load_flags, io_out8, io_store_flags};
+use crate::int::{InterruptIndex, PIC0_IMR, PIC1_IMR, SPURIOUS_VECTOR};
+use crate::timer;
+
+const IA32_APIC_BASE: u32 = 0x1b;
+const APIC_BASE_ENABLE: u64 = 1 << 11;
+const APIC_BASE_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000;
+//IO-APIC的标准地址（未解析ACPI的MADT）
+const IOAPIC_PHYS_ADDR: u64 = 0xfec0_0000;
+
+//寄存器映射到的虚拟地址
+pub const LAPIC_VIRT_ADDR: u64 = 0x_4100_0000_0000;
+pub const IOAPIC_VIRT_ADDR: u64 = LAPIC_VIRT_ADDR + 0x1000;
+
+//LAPIC寄存器的偏移
+const LAPIC_ID: u64 = 0x020;
+const LAPIC_EOI: u64 = 0x0b0;
+const LAPIC_SVR: u64 = 0x0f0;
+const LAPIC_LVT_TIMER: u64 = 0x320;
+const LAPIC_LVT_LINT0: u64 = 0x350;
+const LAPIC_TIMER_INIT: u64 = 0x380;
+const LAPIC_TIMER_CURRENT: u64 = 0x390;
+const LAPIC_TIMER_DIVIDE: u64 = 0x3e0;
+
+const LAPIC_SVR_ENABLE: u32 = 1 << 8;
+const LVT_MASKED: u32 = 1 << 16;
+const LVT_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0x3;
+
+//IO-APIC的间接访问寄存器
+const IOAPIC_REGSEL: u64 = 0x00;
+const IOAPIC_WIN: u64 = 0x10;
+const IOAPIC_REDTBL: u32 = 0x10;
+
+const PORT_KEYDAT: u16 = 0x60;
+const PORT_KEYSTA: u16 = 0x64;
+
+//校准LAPIC定时器时测量的PIT tick数
+const CALIBRATE_TICKS: u64 = 5;
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn is_supported() -> bool {
+    let cpuid = unsafe { __cpuid(1) };
+    cpuid.edx & (1 << 9) != 0
+}
+
+//是否已从8259 PIC切换到APIC
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Acquire)
+}
+
+fn lapic_read(reg: u64) -> u32 {
+    unsafe { read_volatile((LAPIC_VIRT_ADDR + reg) as *const u32) }
+}
+
+fn lapic_write(reg: u64, value: u32) {
+    unsafe { write_volatile((LAPIC_VIRT_ADDR + reg) as *mut u32, value) }
+}
+
+fn ioapic_write(reg: u32, value: u32) {
+    unsafe {
+        write_volatile((IOAPIC_VIRT_ADDR + IOAPIC_REGSEL) as *mut u32, reg);
+        write_volatile((IOAPIC_VIRT_ADDR + IOAPIC_WIN) as *mut u32, value);
+    }
+}
+
+//将ISA的irq以vector转发给apic_id的LAPIC
+//固定模式、物理目标、高电平有效、边沿触发，ISA的irq号与GSI相同
+fn ioapic_route(irq: u8, vector: u8, apic_id: u8) {
+    let reg = IOAPIC_REDTBL + irq as u32 * 2;
+    ioapic_write(reg + 1, (apic_id as u32) << 24);
+    ioapic_write(reg, vector as u32);
+}
+
+//将irq转发给当前的CPU
+pub fn route_irq(irq: u8, vector: u8) {
+    let apic_id = (lapic_read(LAPIC_ID) >> 24) as u8;
+    ioapic_route(irq, vector, apic_id);
+}
+
+fn map_mmio(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+    virt: u64,
+    phys: u64
+) -> Result<(), &'static str> {
+    let page: Page<Size4KiB> = Page::containing_address(VirtAddr::new(virt));
+    let frame = PhysFrame::containing_address(PhysAddr::new(phys));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    unsafe {
+        mapper.map_to(page, frame, flags, frame_allocator)
+            .map_err(|_| "Failed to map APIC registers")?
+            .flush();
+    }
+    Ok(())
+}
+
+//以正在运行的PIT为基准，测量一个tick内LAPIC定时器的计数
+//需要在开中断时调用
+fn calibrate_timer() -> u32 {
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_LVT_TIMER, LVT_MASKED);
+    //从tick的边界开始计数
+    let start = timer::uptime();
+    while timer::uptime() == start {
+        io_hlt();
+    }
+    lapic_write(LAPIC_TIMER_INIT, u32::MAX);
+    let start = timer::uptime();
+    while timer::uptime().wrapping_sub(start) < CALIBRATE_TICKS {
+        io_hlt();
+    }
+    let elapsed = u32::MAX - lapic_read(LAPIC_TIMER_CURRENT);
+    lapic_write(LAPIC_TIMER_INIT, 0);
+    (elapsed / CALIBRATE_TICKS as u32).max(1)
+}
+
+//启用LAPIC，屏蔽8259 PIC，通过IO-APIC转发键盘和鼠标中断
+//LAPIC定时器以与PIT相同的频率产生定时器中断，因此pit::frequency()仍然有效
+//失败时继续使用8259 PIC；需要在开中断、PIT运行时调用
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>
+) -> Result<(), &'static str> {
+    if !is_supported() {
+        return Err("APIC is not supported");
+    }
+    let mut msr = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { msr.read() };
+    map_mmio(mapper, frame_allocator, LAPIC_VIRT_ADDR, base & APIC_BASE_ADDR_MASK)?;
+    map_mmio(mapper, frame_allocator, IOAPIC_VIRT_ADDR, IOAPIC_PHYS_ADDR)?;
+    unsafe { msr.write(base | APIC_BASE_ENABLE); }
+    lapic_write(LAPIC_SVR, LAPIC_SVR_ENABLE | SPURIOUS_VECTOR as u32);
+
+    let count = calibrate_timer();
+
+    let eflags = io_load_flags();
+    io_cli();
+    io_out8(PIC0_IMR, 0xff);
+    io_out8(PIC1_IMR, 0xff);
+    //不再接收经由LINT0的PIC中断
+    lapic_write(LAPIC_LVT_LINT0, LVT_MASKED);
+    route_irq(InterruptIndex::Keyboard.irq(), InterruptIndex::Keyboard.as_u8());
+    route_irq(InterruptIndex::Mouse.irq(), InterruptIndex::Mouse.as_u8());
+    //切换前残留在PIC中的中断不会再送达，读出缓冲区中的数据使之后的中断能够产生
+    while io_in8(PORT_KEYSTA) & 0x01 != 0 {
+        io_in8(PORT_KEYDAT);
+    }
+    lapic_write(LAPIC_TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    lapic_write(LAPIC_LVT_TIMER, LVT_PERIODIC | InterruptIndex::Timer.as_u8() as u32);
+    lapic_write(LAPIC_TIMER_INIT, count);
+    ENABLED.store(true, Ordering::Release);
+    io_store_flags(eflags);
+    Ok(())
+}
+
+pub fn end_of_interrupt() {
+    lapic_write(LAPIC_EOI, 0);
+}
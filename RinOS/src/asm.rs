@@ -26,6 +26,13 @@ pub fn io_out8(port: u16, data: u8) {
     }
 }
 
+pub fn io_out16(port: u16, data: u16) {
+    unsafe {
+        let mut p = Port::new(port);
+        p.write(data);
+    }
+}
+
 pub fn io_in8(port: u16) -> u8 {
     let ret: u8;
     unsafe {
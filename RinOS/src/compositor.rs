@@ -0,0 +1,91 @@
+use x86_64::instructions::interrupts;
+use crate::event::Event;
+use crate::fifo::Fifo;
+use crate::layer::{mouse_layer_index, LAYERCTL};
+use crate::mouse::MousePacket;
+use crate::pointer::{self, Pointer, HOTSPOT_X, HOTSPOT_Y};
+use crate::timer::TimerHandle;
+use crate::window::WindowCtl;
+use crate::task;
+
+const FRAME_TIMER: u32 = 1;
+//一帧的时间，期间的移动合并为一次
+const FRAME_MS: u64 = 16;
+
+//鼠标中断写入解码后的数据包
+pub static MOUSE_FIFO: Fifo<Event, 128> = Fifo::new();
+
+//一帧内累积的鼠标数据
+struct Motion {
+    dx: i32,
+    dy: i32,
+    dz: i32,
+    buttons: u8,
+}
+
+impl Motion {
+    fn is_empty(&self) -> bool {
+        self.dx == 0 && self.dy == 0 && self.dz == 0
+    }
+}
+
+//移动光标图层，再处理窗口的点击和拖动
+fn flush(window_ctl: &mut WindowCtl, pointer: &mut Pointer, mouse_layer: usize, motion: &mut Motion) {
+    let moved = pointer.apply(motion.dx, -motion.dy, &pointer::config());
+    let (x, y) = pointer.position();
+    let (sprite_x, sprite_y) = pointer.sprite_origin();
+    let packet = MousePacket {
+        dx: motion.dx.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+        dy: motion.dy.clamp(i16::MIN as i32, i16::MAX as i32) as i16,
+        dz: motion.dz.clamp(i8::MIN as i32, i8::MAX as i32) as i8,
+        buttons: motion.buttons,
+    };
+    interrupts::without_interrupts(|| {
+        let mut layerctl = LAYERCTL.lock();
+        if moved {
+            layerctl.slide(mouse_layer, sprite_x, sprite_y);
+        }
+        window_ctl.on_mouse(&mut layerctl, mouse_layer, x, y, &packet);
+    });
+    motion.dx = 0;
+    motion.dy = 0;
+    motion.dz = 0;
+}
+
+//合成任务：合并鼠标的移动，每帧移动一次光标
+//按键变化时立即处理，使点击发生在正确的位置
+pub fn compositor_task() -> ! {
+    MOUSE_FIFO.set_task(Some(task::current()));
+    let (mouse_layer, x0, y0) = interrupts::without_interrupts(|| {
+        let mouse_layer = *mouse_layer_index.lock();
+        let cursor = LAYERCTL.lock().layer_data[mouse_layer];
+        (mouse_layer, cursor.x0 as isize, cursor.y0 as isize)
+    });
+    let mut pointer = Pointer::new(x0 + HOTSPOT_X, y0 + HOTSPOT_Y);
+    let frame_timer = TimerHandle::new(&MOUSE_FIFO, FRAME_TIMER).unwrap();
+    let mut window_ctl = WindowCtl::new();
+    let mut motion = Motion { dx: 0, dy: 0, dz: 0, buttons: 0 };
+
+    loop {
+        match MOUSE_FIFO.get_blocking() {
+            Event::Mouse(packet) => {
+                motion.dx += packet.dx as i32;
+                motion.dy += packet.dy as i32;
+                motion.dz += packet.dz as i32;
+                if packet.buttons != motion.buttons {
+                    motion.buttons = packet.buttons;
+                    frame_timer.cancel();
+                    flush(&mut window_ctl, &mut pointer, mouse_layer, &mut motion);
+                } else if !frame_timer.is_running() {
+                    frame_timer.set_time_ms(FRAME_MS);
+                }
+            }
+            Event::Timer(FRAME_TIMER) => {
+                if !motion.is_empty() {
+                    flush(&mut window_ctl, &mut pointer, mouse_layer, &mut motion);
+                }
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,602 @@
+use core::fmt;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use pc_keyboard::KeyCode;
+use vga::colors::Color16;
+use x86_64::instructions::interrupts;
+use crate::compositor::MOUSE_FIFO;
+use crate::event::{Event, EVENT_FIFO};
+use crate::fifo::Fifo;
+use crate::font::{FONT_HEIGHT, FONT_WIDTH};
+use crate::layer::LAYERCTL;
+use crate::timer::{self, TimerHandle};
+use crate::ansi::{Action, AnsiParser, Attr, Csi};
+use crate::vga::{boxfill, putfont, WindowWrite};
+use crate::keyboard::{self, KeyEvent, Layout, MOD_CTRL};
+use crate::{memory, pit, pointer, serial_print, task, tsc, window, window_print, window_println};
+
+pub const CONSOLE_COLS: usize = 60;
+pub const CONSOLE_ROWS: usize = 16;
+//保存的行数，包括屏幕上的行
+pub const CONSOLE_SCROLLBACK: usize = 256;
+//文字区域在窗口中的位置
+const TEXT_X: usize = 8;
+const TEXT_Y: usize = 28;
+const WINDOW_WIDTH: usize = CONSOLE_COLS * FONT_WIDTH + 16;
+const WINDOW_HEIGHT: usize = CONSOLE_ROWS * FONT_HEIGHT + 37;
+const TEXT_COLOR: Color16 = Color16::White;
+const BACK_COLOR: Color16 = Color16::Black;
+
+const CURSOR_TIMER: u32 = 1;
+//滚轮每格翻过的行数
+const WHEEL_LINES: isize = 3;
+const PROMPT: &str = "> ";
+//主任务在按下Shift+PageUp/PageDown时发送的消息
+pub const MSG_PAGE_UP: u32 = 1;
+pub const MSG_PAGE_DOWN: u32 = 2;
+
+const DEFAULT_ATTR: Attr = Attr::new(TEXT_COLOR, Some(BACK_COLOR));
+
+//一个字符及其颜色
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cell {
+    pub ch: u8,
+    pub fg: Color16,
+    pub bg: Color16,
+}
+
+impl Cell {
+    //以attr的背景色清除
+    fn blank(attr: Attr) -> Cell {
+        Cell { ch: b' ', fg: attr.fg(), bg: attr.bg.unwrap_or(BACK_COLOR) }
+    }
+}
+
+const BLANK: Cell = Cell { ch: b' ', fg: TEXT_COLOR, bg: BACK_COLOR };
+
+//主任务将键盘输入转发到该队列
+pub static CONSOLE_FIFO: Fifo<Event, 128> = Fifo::new();
+
+pub struct Console {
+    buf: Vec<Color16>,
+    layer: usize,
+    lines: Vec<[Cell; CONSOLE_COLS]>, //环形缓冲区
+    head: usize, //最早的行在lines中的位置
+    count: usize, //保存的行数，不少于CONSOLE_ROWS
+    scroll: usize, //从最新的画面向上翻过的行数
+    cur_x: usize,
+    cur_y: usize, //光标在最新画面中的行
+    cursor_on: bool, //光标闪烁的状态
+    cursor_at: Option<(usize, usize)>, //已经画出光标的位置
+    dirty: Option<(usize, usize)>, //需要刷新的行的范围
+    attr: Attr, //由SGR设定的颜色
+    parser: AnsiParser,
+    mirror: bool, //同时输出到串口
+}
+
+impl Console {
+    //创建窗口图层，放在鼠标图层的下面
+    pub fn new(caption: &str, x: usize, y: usize) -> Option<Console> {
+        let mut buf = vec![BACK_COLOR; WINDOW_WIDTH * WINDOW_HEIGHT];
+        window::make_window(&mut buf, WINDOW_WIDTH, WINDOW_HEIGHT, caption);
+        boxfill(
+            &mut buf,
+            BACK_COLOR,
+            TEXT_X,
+            TEXT_Y,
+            TEXT_X + CONSOLE_COLS * FONT_WIDTH - 1,
+            TEXT_Y + CONSOLE_ROWS * FONT_HEIGHT - 1,
+            WINDOW_WIDTH
+        );
+        let layer = interrupts::without_interrupts(|| {
+            let mut layerctl = LAYERCTL.lock();
+            let layer = layerctl.alloc()?;
+            layerctl.set_buf(layer, &mut buf, WINDOW_WIDTH, WINDOW_HEIGHT, None);
+            layerctl.slide(layer, x, y);
+            let z = layerctl.z_max.unwrap_or(0);
+            layerctl.up_down(layer, Some(z));
+            Some(layer)
+        })?;
+        window::register(layer, &buf, Some(&CONSOLE_FIFO));
+        Some(Console {
+            buf,
+            layer,
+            lines: vec![[BLANK; CONSOLE_COLS]; CONSOLE_SCROLLBACK],
+            head: 0,
+            count: CONSOLE_ROWS,
+            scroll: 0,
+            cur_x: 0,
+            cur_y: 0,
+            cursor_on: true,
+            cursor_at: None,
+            dirty: None,
+            attr: DEFAULT_ATTR,
+            parser: AnsiParser::new(),
+            mirror: false,
+        })
+    }
+
+    //释放图层，之后缓冲区随Console一起释放
+    pub fn close(self) {
+        window::close(self.layer);
+    }
+
+    fn mark_dirty(&mut self, y: usize) {
+        self.dirty = match self.dirty {
+            Some((y0, y1)) => Some((y0.min(y), y1.max(y))),
+            None => Some((y, y)),
+        };
+    }
+
+    //第n行（最早的行为0）在lines中的位置
+    fn line_index(&self, n: usize) -> usize {
+        (self.head + n) % CONSOLE_SCROLLBACK
+    }
+
+    //屏幕上第y行显示的行
+    fn screen_line(&self, y: usize) -> usize {
+        self.line_index(self.count - CONSOLE_ROWS - self.scroll + y)
+    }
+
+    fn cursor_line(&self) -> usize {
+        self.line_index(self.count - CONSOLE_ROWS + self.cur_y)
+    }
+
+    fn draw_row(&mut self, y: usize) {
+        let py = TEXT_Y + y * FONT_HEIGHT;
+        let line = self.screen_line(y);
+        for x in 0..CONSOLE_COLS {
+            let cell = self.lines[line][x];
+            let px = TEXT_X + x * FONT_WIDTH;
+            boxfill(&mut self.buf, cell.bg, px, py, px + FONT_WIDTH - 1, py + FONT_HEIGHT - 1, WINDOW_WIDTH);
+            putfont(&mut self.buf, px, py, cell.fg, cell.ch as char, WINDOW_WIDTH);
+        }
+        self.mark_dirty(y);
+    }
+
+    fn hide_cursor(&mut self) {
+        if let Some((_, y)) = self.cursor_at.take() {
+            self.draw_row(y);
+        }
+    }
+
+    fn draw_all(&mut self) {
+        for y in 0..CONSOLE_ROWS {
+            self.draw_row(y);
+        }
+    }
+
+    //翻阅历史时不显示光标
+    fn show_cursor(&mut self) {
+        if !self.cursor_on || self.cursor_at.is_some() || self.scroll > 0 {
+            return;
+        }
+        let px = TEXT_X + self.cur_x * FONT_WIDTH;
+        let py = TEXT_Y + self.cur_y * FONT_HEIGHT;
+        boxfill(&mut self.buf, TEXT_COLOR, px, py, px + FONT_WIDTH - 1, py + FONT_HEIGHT - 1, WINDOW_WIDTH);
+        self.cursor_at = Some((self.cur_x, self.cur_y));
+        self.mark_dirty(self.cur_y);
+    }
+
+    //将修改过的行画到屏幕上
+    pub fn flush(&mut self) {
+        self.show_cursor();
+        if let Some((y0, y1)) = self.dirty.take() {
+            interrupts::without_interrupts(|| {
+                LAYERCTL.lock().refresh(
+                    self.layer,
+                    TEXT_X,
+                    TEXT_Y + y0 * FONT_HEIGHT,
+                    TEXT_X + CONSOLE_COLS * FONT_WIDTH,
+                    TEXT_Y + (y1 + 1) * FONT_HEIGHT
+                );
+            });
+        }
+    }
+
+    pub fn blink(&mut self) {
+        self.cursor_on = !self.cursor_on;
+        self.hide_cursor();
+        self.flush();
+    }
+
+    //文字区域的像素整体上移一行
+    fn scroll_buf_up(&mut self) {
+        let width = CONSOLE_COLS * FONT_WIDTH;
+        for py in TEXT_Y..TEXT_Y + (CONSOLE_ROWS - 1) * FONT_HEIGHT {
+            let src = (py + FONT_HEIGHT) * WINDOW_WIDTH + TEXT_X;
+            self.buf.copy_within(src..src + width, py * WINDOW_WIDTH + TEXT_X);
+        }
+        self.mark_dirty(0);
+    }
+
+    //到达最后一行时追加一行，超过容量时丢弃最早的行
+    fn new_line(&mut self) {
+        self.cur_x = 0;
+        if self.cur_y + 1 < CONSOLE_ROWS {
+            self.cur_y += 1;
+            return;
+        }
+        if self.count < CONSOLE_SCROLLBACK {
+            self.count += 1;
+        } else {
+            self.head = (self.head + 1) % CONSOLE_SCROLLBACK;
+        }
+        let line = self.cursor_line();
+        self.lines[line] = [Cell::blank(self.attr); CONSOLE_COLS];
+        self.scroll_buf_up();
+        self.draw_row(CONSOLE_ROWS - 1);
+    }
+
+    //有输出时回到最新的画面
+    fn scroll_to_bottom(&mut self) {
+        if self.scroll > 0 {
+            self.scroll = 0;
+            self.draw_all();
+        }
+    }
+
+    //lines为正时向上（较早的行）翻
+    pub fn scroll_by(&mut self, lines: isize) {
+        let max = (self.count - CONSOLE_ROWS) as isize;
+        let scroll = (self.scroll as isize + lines).clamp(0, max) as usize;
+        if scroll != self.scroll {
+            if self.scroll == 0 {
+                self.hide_cursor();
+            }
+            self.scroll = scroll;
+            self.draw_all();
+        }
+        self.flush();
+    }
+
+    pub fn page_up(&mut self) {
+        self.scroll_by(CONSOLE_ROWS as isize);
+    }
+
+    pub fn page_down(&mut self) {
+        self.scroll_by(-(CONSOLE_ROWS as isize));
+    }
+
+    pub fn put_char(&mut self, c: u8) {
+        self.hide_cursor();
+        self.scroll_to_bottom();
+        if c == b'\n' {
+            self.new_line();
+            return;
+        }
+        //只能显示ASCII的可见字符
+        let c = if c.is_ascii_graphic() { c } else { b' ' };
+        let line = self.cursor_line();
+        self.lines[line][self.cur_x] = Cell { ch: c, fg: self.attr.fg(), bg: self.attr.bg.unwrap_or(BACK_COLOR) };
+        self.draw_row(self.cur_y);
+        self.cur_x += 1;
+        if self.cur_x == CONSOLE_COLS {
+            self.new_line();
+        }
+    }
+
+    //清除屏幕上从(x0, y0)到(x1, y1)之前的字符
+    fn erase(&mut self, x0: usize, y0: usize, x1: usize, y1: usize) {
+        let blank = Cell::blank(self.attr);
+        for y in y0..=y1.min(CONSOLE_ROWS - 1) {
+            let line = self.screen_line(y);
+            let from = if y == y0 { x0 } else { 0 };
+            let to = if y == y1 { x1 } else { CONSOLE_COLS };
+            for x in from..to.min(CONSOLE_COLS) {
+                self.lines[line][x] = blank;
+            }
+            self.draw_row(y);
+        }
+    }
+
+    fn control(&mut self, c: u8) {
+        match c {
+            b'\n' => self.put_char(b'\n'),
+            b'\r' => self.cur_x = 0,
+            0x08 => self.cur_x = self.cur_x.saturating_sub(1),
+            b'\t' => {
+                let x = (self.cur_x / 8 + 1) * 8;
+                while self.cur_x < x.min(CONSOLE_COLS - 1) {
+                    self.put_char(b' ');
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, csi: &Csi) {
+        let n = csi.param(0, 1) as usize;
+        let (x, y) = (self.cur_x, self.cur_y);
+        match csi.command {
+            b'A' => self.cur_y = y.saturating_sub(n),
+            b'B' => self.cur_y = (y + n).min(CONSOLE_ROWS - 1),
+            b'C' => self.cur_x = (x + n).min(CONSOLE_COLS - 1),
+            b'D' => self.cur_x = x.saturating_sub(n),
+            b'H' | b'f' => {
+                self.cur_y = (csi.param(0, 1) as usize - 1).min(CONSOLE_ROWS - 1);
+                self.cur_x = (csi.param(1, 1) as usize - 1).min(CONSOLE_COLS - 1);
+            }
+            b'J' => match csi.param(0, 0) {
+                0 => self.erase(x, y, CONSOLE_COLS, CONSOLE_ROWS - 1),
+                1 => self.erase(0, 0, x + 1, y),
+                _ => self.erase(0, 0, CONSOLE_COLS, CONSOLE_ROWS - 1),
+            },
+            b'K' => match csi.param(0, 0) {
+                0 => self.erase(x, y, CONSOLE_COLS, y),
+                1 => self.erase(0, y, x + 1, y),
+                _ => self.erase(0, y, CONSOLE_COLS, y),
+            },
+            b'm' => self.attr.apply_sgr(csi, DEFAULT_ATTR),
+            _ => {}
+        }
+    }
+
+    //解释ANSI的转义序列
+    pub fn write_byte(&mut self, b: u8) {
+        match self.parser.feed(b) {
+            Some(Action::Print(c)) => self.put_char(c),
+            Some(Action::Control(c)) => {
+                self.hide_cursor();
+                self.scroll_to_bottom();
+                self.control(c);
+            }
+            Some(Action::Csi(csi)) => {
+                self.hide_cursor();
+                self.scroll_to_bottom();
+                self.csi(&csi);
+            }
+            None => {}
+        }
+    }
+
+    pub fn put_str(&mut self, s: &str) {
+        let _ = fmt::Write::write_str(self, s);
+        self.flush();
+    }
+
+    pub fn set_mirror(&mut self, mirror: bool) {
+        self.mirror = mirror;
+    }
+
+    //删除光标前的一个字符，可以回到上一行
+    pub fn backspace(&mut self) {
+        self.hide_cursor();
+        self.scroll_to_bottom();
+        if self.cur_x > 0 {
+            self.cur_x -= 1;
+        } else if self.cur_y > 0 {
+            self.cur_y -= 1;
+            self.cur_x = CONSOLE_COLS - 1;
+        } else {
+            return;
+        }
+        let line = self.cursor_line();
+        self.lines[line][self.cur_x] = Cell::blank(self.attr);
+        self.draw_row(self.cur_y);
+    }
+
+    //同时清除历史
+    pub fn clear(&mut self) {
+        self.cursor_at = None;
+        let blank = Cell::blank(self.attr);
+        for line in self.lines.iter_mut() {
+            *line = [blank; CONSOLE_COLS];
+        }
+        self.head = 0;
+        self.count = CONSOLE_ROWS;
+        self.scroll = 0;
+        self.cur_x = 0;
+        self.cur_y = 0;
+        self.draw_all();
+    }
+
+    //输入时光标保持显示
+    fn reset_blink(&mut self) {
+        self.cursor_on = true;
+    }
+}
+
+impl fmt::Write for Console {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        if self.mirror {
+            serial_print!("{}", s);
+        }
+        for b in s.bytes() {
+            self.write_byte(b);
+        }
+        Ok(())
+    }
+}
+
+impl WindowWrite for Console {
+    fn flush(&mut self) {
+        Console::flush(self);
+    }
+}
+
+fn cmd_mem(console: &mut Console) {
+    let (used, free) = rin_os::allocator::heap_usage();
+    window_println!(console, "total   {} KB", memory::usable_memory() / 1024);
+    window_println!(console, "heap    {} KB used, {} KB free", used / 1024, free / 1024);
+    //队列满而被丢弃的事件数
+    window_println!(
+        console,
+        "overrun event {}, mouse {}, console {}",
+        EVENT_FIFO.overruns(),
+        MOUSE_FIFO.overruns(),
+        CONSOLE_FIFO.overruns()
+    );
+}
+
+fn cmd_ticks(console: &mut Console) {
+    let ticks = timer::uptime();
+    let hz = pit::frequency() as u64;
+    window_println!(console, "ticks   {} ({}.{:02} s at {} Hz)", ticks, ticks / hz, ticks % hz * 100 / hz, hz);
+    window_println!(console, "tsc     {} ns", tsc::now_ns());
+}
+
+fn cmd_layers(console: &mut Console) {
+    //输出时会刷新图层，先在锁内复制
+    let layers = interrupts::without_interrupts(|| {
+        let layerctl = LAYERCTL.lock();
+        let mut layers = Vec::new();
+        if let Some(z_max) = layerctl.z_max {
+            for z in 0..=z_max {
+                let index = layerctl.layers[z];
+                layers.push((z, index, layerctl.layer_data[index]));
+            }
+        }
+        layers
+    });
+    for (z, index, layer) in layers {
+        window_println!(console, "z={:<3} layer={:<3} ({}, {}) {}x{}", z, index, layer.x0, layer.y0, layer.xsize, layer.ysize);
+    }
+}
+
+//不带参数时显示当前的布局和可选的布局
+fn cmd_keymap(console: &mut Console, args: &str) {
+    if args.is_empty() {
+        window_print!(console, "keymap  {} (", keyboard::layout().name());
+        for (i, layout) in Layout::ALL.iter().enumerate() {
+            let sep = if i == 0 { "" } else { " " };
+            window_print!(console, "{}{}", sep, layout.name());
+        }
+        window_println!(console, ")");
+        return;
+    }
+    match Layout::from_name(args) {
+        Some(layout) => keyboard::set_layout(layout),
+        None => window_println!(console, "Unknown keymap: {}", args),
+    }
+}
+
+//typematic 速率(0～31) 延迟(0～3)
+fn cmd_typematic(console: &mut Console, args: &str) {
+    let mut it = args.split_whitespace().map(|a| a.parse::<u8>());
+    let result = match (it.next(), it.next(), it.next()) {
+        (Some(Ok(rate)), Some(Ok(delay)), None) => keyboard::set_typematic(rate, delay),
+        _ => Err("Usage: typematic <rate 0-31> <delay 0-3>"),
+    };
+    if let Err(e) = result {
+        window_println!(console, "{}", e);
+    }
+}
+
+//pointer [sensitivity|accel|threshold 值]，不带参数时显示当前的设定
+fn cmd_pointer(console: &mut Console, args: &str) {
+    let mut config = pointer::config();
+    let mut it = args.split_whitespace();
+    match (it.next(), it.next().map(|v| v.parse::<u32>()), it.next()) {
+        (None, _, _) => {}
+        (Some(name), Some(Ok(value)), None) => {
+            match name {
+                "sensitivity" if (10..=1000).contains(&value) => config.sensitivity = value,
+                "accel" if value <= 100 => config.accel = value,
+                "threshold" if value <= 100 => config.threshold = value,
+                _ => {
+                    window_println!(console, "Invalid setting: {} {}", name, value);
+                    return;
+                }
+            }
+            pointer::set_config(config);
+        }
+        _ => {
+            window_println!(console, "Usage: pointer [sensitivity 10-1000 | accel 0-100 | threshold 0-100]");
+            return;
+        }
+    }
+    window_println!(
+        console,
+        "sensitivity {}%  accel {}%  threshold {}",
+        config.sensitivity,
+        config.accel,
+        config.threshold
+    );
+}
+
+pub fn run_command(console: &mut Console, line: &str) {
+    let line = line.trim();
+    let (cmd, args) = match line.find(' ') {
+        Some(i) => (&line[..i], line[i + 1..].trim_start()),
+        None => (line, ""),
+    };
+    match cmd {
+        "" => {}
+        "mem" => cmd_mem(console),
+        "cls" => console.clear(),
+        "ticks" => cmd_ticks(console),
+        "layers" => cmd_layers(console),
+        //\e为ESC，可以输出ANSI的转义序列
+        "echo" => {
+            console.put_str(&args.replace("\\e", "\x1b"));
+            console.put_str("\n");
+        }
+        "keymap" => cmd_keymap(console, args),
+        "typematic" => cmd_typematic(console, args),
+        "pointer" => cmd_pointer(console, args),
+        "serial" => match args {
+            "on" => console.set_mirror(true),
+            "off" => console.set_mirror(false),
+            _ => console.put_str("usage: serial on|off\n"),
+        },
+        _ => console.put_str("Bad command.\n"),
+    }
+}
+
+//控制台任务：显示键盘输入并执行命令
+pub fn console_task() -> ! {
+    CONSOLE_FIFO.set_task(Some(task::current()));
+    let mut console = Console::new("console", 136, 140).unwrap();
+    let cursor_timer = TimerHandle::new(&CONSOLE_FIFO, CURSOR_TIMER).unwrap();
+    cursor_timer.set_periodic_ms(500);
+    let mut line = String::new();
+    console.put_str(PROMPT);
+
+    loop {
+        match CONSOLE_FIFO.get_blocking() {
+            Event::Timer(CURSOR_TIMER) => console.blink(),
+            Event::User(MSG_PAGE_UP) => console.page_up(),
+            Event::User(MSG_PAGE_DOWN) => console.page_down(),
+            Event::Wheel(dz) => console.scroll_by(-(dz as isize) * WHEEL_LINES),
+            Event::Close(_) => {
+                drop(cursor_timer);
+                console.close();
+                CONSOLE_FIFO.set_task(None);
+                task::exit();
+            }
+            //Ctrl+C放弃正在输入的行
+            Event::Input(KeyEvent { code: KeyCode::C, pressed: true, modifiers, .. }) if modifiers & MOD_CTRL != 0 => {
+                console.reset_blink();
+                console.put_str("^C\n");
+                line.clear();
+                console.put_str(PROMPT);
+                console.flush();
+            }
+            Event::Input(KeyEvent { pressed: true, ch: Some(c), .. }) => {
+                console.reset_blink();
+                match c {
+                    '\n' => {
+                        console.put_char(b'\n');
+                        run_command(&mut console, &line);
+                        line.clear();
+                        console.put_str(PROMPT);
+                    }
+                    '\u{8}' => {
+                        if line.pop().is_some() {
+                            console.backspace();
+                        }
+                    }
+                    c if c.is_ascii_graphic() || c == ' ' => {
+                        line.push(c);
+                        console.put_char(c as u8);
+                    }
+                    _ => {}
+                }
+                console.flush();
+            }
+            _ => {}
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use crate::fifo::Fifo;
+use crate::keyboard::KeyEvent;
+use crate::mouse::MousePacket;
+use crate::window::ButtonEvent;
+
+const EVENT_FIFO_SIZE: usize = 128;
+
+//任务通过Fifo<Event, N>接收的事件
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    Key(u8), //键盘扫描码
+    Input(KeyEvent), //主任务解码后转发给窗口的按键
+    Mouse(MousePacket),
+    Timer(u32), //定时器设定的data
+    User(u32), //任务之间传递的消息
+    Close(usize), //点击了关闭按钮，参数为窗口的图层
+    Wheel(i8), //发给焦点窗口的滚轮移动量，向下为正
+    Button(ButtonEvent), //光标下的窗口中按下或松开了鼠标按键
+}
+
+//定时器只需要写入事件，通过该trait使用不同容量的队列
+pub trait EventSink {
+    fn put_event(&self, event: Event) -> Result<(), &'static str>;
+}
+
+impl<const N: usize> EventSink for Fifo<Event, N> {
+    fn put_event(&self, event: Event) -> Result<(), &'static str> {
+        self.put(event)
+    }
+}
+
+//键盘和鼠标中断写入的队列，由主任务处理
+pub static EVENT_FIFO: Fifo<Event, EVENT_FIFO_SIZE> = Fifo::new();
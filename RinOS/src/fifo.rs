@@ -1,57 +1,102 @@
-use core::cell::{Cell, RefCell};
+use alloc::vec::Vec;
+use core::ptr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::asm::{io_cli, io_load_flags, io_store_flags, io_stihlt};
+use crate::ring::RingBuffer;
+use crate::task;
 
-const FLAGS_OVERRUN: u32 = 0x0001;
+const NO_TASK: usize = usize::MAX;
 
-pub struct Fifo {
-    pub buf: RefCell<[u8; 128]>,
-    pub p: Cell<u32>,
-    pub q: Cell<u32>,
-    pub free: Cell<u32>,
-    pub flags: Cell<u32>,
-    pub size: u32
+//设定过所属任务的队列的task，任务结束时解除
+static BINDINGS: Mutex<Vec<&'static AtomicUsize>> = Mutex::new(Vec::new());
+
+//容量N在编译时确定
+//数据由中断或任务写入，只由所属任务读出
+pub struct Fifo<T: Copy, const N: usize> {
+    ring: RingBuffer<T, N>,
+    task: AtomicUsize, //有数据写入时唤醒的任务
 }
 
-impl Fifo {
-    pub fn new(size: u32) -> Fifo {
+impl<T: Copy, const N: usize> Fifo<T, N> {
+    pub const fn new() -> Fifo<T, N> {
         Fifo {
-            size,
-            p: Cell::new(0),
-            q: Cell::new(0),
-            flags: Cell::new(0),
-            free: Cell::new(size),
-            buf: RefCell::new([0; 128])
+            ring: RingBuffer::new(),
+            task: AtomicUsize::new(NO_TASK),
         }
     }
 
-    pub fn put(&self, data: u8) -> Result<(), &'static str> {
-        if self.free.get() == 0 {
-            self.flags.set(self.flags.get() | FLAGS_OVERRUN);
-            return Err("FLAGS_OVERRUN error");
-        }
-        let mut buf = self.buf.borrow_mut();
-        buf[self.p.get() as usize] = data;
-        self.p.set(self.p.get() + 1);
-        if self.p.get() == self.size {
-            self.p.set(0);
-        }
-        self.free.set(self.free.get() - 1);
-        Ok(())
+    pub fn set_task(&'static self, task: Option<usize>) {
+        interrupts::without_interrupts(|| {
+            let mut bindings = BINDINGS.lock();
+            if !bindings.iter().any(|&binding| ptr::eq(binding, &self.task)) {
+                bindings.push(&self.task);
+            }
+        });
+        self.task.store(task.unwrap_or(NO_TASK), Ordering::Release);
     }
 
-    pub fn get(&self) -> Result<u8, &'static str> {
-        if self.free.get() == self.size {
-            return Err("Buffer is empty");
+    pub fn task(&self) -> Option<usize> {
+        match self.task.load(Ordering::Acquire) {
+            NO_TASK => None,
+            task => Some(task),
         }
-        let data = self.buf.borrow()[self.q.get() as usize];
-        self.q.set(self.q.get() + 1);
-        if self.q.get() == self.size {
-            self.q.set(0);
+    }
+
+    pub fn put(&self, data: T) -> Result<(), &'static str> {
+        //关中断，使任务中的写入不会与中断处理程序中的写入交错
+        interrupts::without_interrupts(|| {
+            //单CPU上关中断时没有其他的写入者
+            if unsafe { self.ring.push(data) }.is_err() {
+                return Err("Buffer overrun");
+            }
+            if let Some(task) = self.task() {
+                //level已满时任务不被唤醒，数据留在队列中
+                let _ = task::run(task, None, None);
+            }
+            Ok(())
+        })
+    }
+
+    //读出同样在关中断时进行，多个任务读同一个队列时也不会交错
+    pub fn get(&self) -> Result<T, &'static str> {
+        interrupts::without_interrupts(|| unsafe { self.ring.pop() }).ok_or("Buffer is empty")
+    }
+
+    //缓冲区为空时让所属任务休眠，直到put唤醒它
+    //应由所属任务调用；没有所属任务时用hlt等待
+    pub fn get_blocking(&self) -> T {
+        loop {
+            let eflags = io_load_flags();
+            io_cli();
+            if let Ok(data) = self.get() {
+                io_store_flags(eflags);
+                return data;
+            }
+            match self.task() {
+                Some(task) => task::sleep(task),
+                None => io_stihlt(),
+            }
+            io_store_flags(eflags);
         }
-        self.free.set(self.free.get() + 1);
-        Ok(data)
     }
 
-    pub fn status(&self) -> u32 {
-        self.size - self.free.get()
+    pub fn status(&self) -> usize {
+        self.ring.len()
+    }
+
+    //缓冲区满而被丢弃的数据数
+    pub fn overruns(&self) -> usize {
+        self.ring.overruns()
     }
 }
+
+//解除所有队列与已结束的任务的关联，任务号会被之后创建的任务重新使用
+pub fn unbind_task(task: usize) {
+    interrupts::without_interrupts(|| {
+        for binding in BINDINGS.lock().iter() {
+            let _ = binding.compare_exchange(task, NO_TASK, Ordering::AcqRel, Ordering::Acquire);
+        }
+    });
+}
@@ -0,0 +1,65 @@
+use crate::asm::{io_in8, io_out16};
+
+//QEMU的fw_cfg接口，用-fw_cfg name=opt/...,string=...在启动时传递设定
+const FW_CFG_SELECTOR: u16 = 0x0510;
+const FW_CFG_DATA: u16 = 0x0511;
+
+const FW_CFG_SIGNATURE: u16 = 0x0000;
+const FW_CFG_FILE_DIR: u16 = 0x0019;
+//文件目录的每一项：大小（4字节）、选择子（2字节）、保留（2字节）、文件名，都是大端序
+const FILE_NAME_LEN: usize = 56;
+
+fn select(key: u16) {
+    io_out16(FW_CFG_SELECTOR, key);
+}
+
+fn read_bytes(buf: &mut [u8]) {
+    for byte in buf.iter_mut() {
+        *byte = io_in8(FW_CFG_DATA);
+    }
+}
+
+fn read_be16() -> u16 {
+    let mut buf = [0; 2];
+    read_bytes(&mut buf);
+    u16::from_be_bytes(buf)
+}
+
+fn read_be32() -> u32 {
+    let mut buf = [0; 4];
+    read_bytes(&mut buf);
+    u32::from_be_bytes(buf)
+}
+
+//不在QEMU上运行时没有fw_cfg，读出的是0xff
+pub fn present() -> bool {
+    select(FW_CFG_SIGNATURE);
+    let mut signature = [0; 4];
+    read_bytes(&mut signature);
+    &signature == b"QEMU"
+}
+
+//把名为name的文件读入buf，返回读出的字节数，超出buf的部分被丢弃
+//只在启动时由一个任务调用
+pub fn read_file(name: &str, buf: &mut [u8]) -> Option<usize> {
+    if !present() {
+        return None;
+    }
+    select(FW_CFG_FILE_DIR);
+    let count = read_be32();
+    for _ in 0..count {
+        let size = read_be32() as usize;
+        let key = read_be16();
+        read_be16();
+        let mut file = [0; FILE_NAME_LEN];
+        read_bytes(&mut file);
+        let len = file.iter().position(|&c| c == 0).unwrap_or(FILE_NAME_LEN);
+        if &file[..len] == name.as_bytes() {
+            let len = size.min(buf.len());
+            select(key);
+            read_bytes(&mut buf[..len]);
+            return Some(len);
+        }
+    }
+    None
+}
@@ -0,0 +1,351 @@
+use core::sync::atomic::{AtomicU8, Ordering};
+use crate::asm::{io_in8, io_out8};
+use crate::event::{Event, EVENT_FIFO};
+use crate::{mouse, pit, serial_println};
+
+const PORT_DATA: u16 = 0x60;
+const PORT_STATUS: u16 = 0x64; //读出时为状态寄存器，写入时为命令寄存器
+
+const STATUS_OUTPUT_FULL: u8 = 0x01;
+const STATUS_INPUT_FULL: u8 = 0x02;
+
+//控制器的命令
+const CMD_READ_CONFIG: u8 = 0x20;
+const CMD_WRITE_CONFIG: u8 = 0x60;
+const CMD_DISABLE_PORT2: u8 = 0xa7;
+const CMD_ENABLE_PORT2: u8 = 0xa8;
+const CMD_TEST_PORT2: u8 = 0xa9;
+const CMD_SELF_TEST: u8 = 0xaa;
+const CMD_TEST_PORT1: u8 = 0xab;
+const CMD_DISABLE_PORT1: u8 = 0xad;
+const CMD_ENABLE_PORT1: u8 = 0xae;
+const CMD_WRITE_PORT2: u8 = 0xd4; //下一个写入0x60的字节发送给第二个端口的设备
+
+//配置字节
+const CONFIG_PORT1_INT: u8 = 0x01;
+const CONFIG_PORT2_INT: u8 = 0x02;
+const CONFIG_PORT1_CLOCK_OFF: u8 = 0x10;
+const CONFIG_PORT2_CLOCK_OFF: u8 = 0x20;
+const CONFIG_TRANSLATION: u8 = 0x40; //将第一个端口的扫描码集2转换为集1
+
+const SELF_TEST_OK: u8 = 0x55;
+const PORT_TEST_OK: u8 = 0x00;
+
+//设备的命令和应答
+const DEV_SET_SCANCODE_SET: u8 = 0xf0;
+const DEV_IDENTIFY: u8 = 0xf2;
+const DEV_SET_SAMPLE_RATE: u8 = 0xf3;
+const DEV_ENABLE_SCANNING: u8 = 0xf4;
+const DEV_DISABLE_SCANNING: u8 = 0xf5;
+const DEV_RESET: u8 = 0xff;
+const DEV_ACK: u8 = 0xfa;
+const DEV_SELF_TEST_OK: u8 = 0xaa;
+
+//等待应答的时间（ms），复位时设备的自检较慢
+const TIMEOUT_MS: u32 = 20;
+const RESET_TIMEOUT_MS: u32 = 1000;
+//依次设定这些采样率后，鼠标切换到4字节的数据包（IntelliMouse）
+const WHEEL_SEQUENCE: [u8; 3] = [200, 100, 80];
+const FIVE_BUTTON_SEQUENCE: [u8; 3] = [200, 200, 80];
+const DEFAULT_SAMPLE_RATE: u8 = 100;
+//1ms的PIT时钟数
+const PIT_COUNT_1MS: u16 = 1193;
+const FLUSH_LIMIT: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Device {
+    None,
+    Keyboard,
+    Mouse,
+    WheelMouse,
+    FiveButtonMouse, //带滚轮和第4、5键
+    Unknown,
+}
+
+impl Device {
+    fn from_u8(value: u8) -> Device {
+        match value {
+            1 => Device::Keyboard,
+            2 => Device::Mouse,
+            3 => Device::WheelMouse,
+            4 => Device::FiveButtonMouse,
+            5 => Device::Unknown,
+            _ => Device::None,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Device::None => "none",
+            Device::Keyboard => "keyboard",
+            Device::Mouse => "mouse",
+            Device::WheelMouse => "wheel mouse",
+            Device::FiveButtonMouse => "5-button mouse",
+            Device::Unknown => "unknown",
+        }
+    }
+
+    pub fn is_mouse(self) -> bool {
+        matches!(self, Device::Mouse | Device::WheelMouse | Device::FiveButtonMouse)
+    }
+
+    //数据包的字节数
+    pub fn packet_size(self) -> usize {
+        match self {
+            Device::WheelMouse | Device::FiveButtonMouse => 4,
+            _ => 3,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port {
+    First,  //IRQ1
+    Second, //IRQ12
+}
+
+impl Ps2Port {
+    fn index(self) -> usize {
+        match self {
+            Ps2Port::First => 0,
+            Ps2Port::Second => 1,
+        }
+    }
+}
+
+//各端口上检测到的设备，中断处理程序据此分发数据，不加锁读出
+static DEVICES: [AtomicU8; 2] = [AtomicU8::new(Device::None as u8), AtomicU8::new(Device::None as u8)];
+
+//等待输入缓冲区变空，控制器不存在或无响应时返回Err
+fn wait_sendready() -> Result<(), &'static str> {
+    for _ in 0..=TIMEOUT_MS {
+        if io_in8(PORT_STATUS) & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+        pit::busy_wait(PIT_COUNT_1MS);
+    }
+    Err("PS/2 controller is not accepting data")
+}
+
+//等待输出缓冲区中有数据，超时时返回None
+fn read_timeout(timeout_ms: u32) -> Option<u8> {
+    for _ in 0..=timeout_ms {
+        if io_in8(PORT_STATUS) & STATUS_OUTPUT_FULL != 0 {
+            return Some(io_in8(PORT_DATA));
+        }
+        pit::busy_wait(PIT_COUNT_1MS);
+    }
+    None
+}
+
+//丢弃输出缓冲区中的数据，状态寄存器一直为满时最多读FLUSH_LIMIT次
+fn flush() {
+    for _ in 0..FLUSH_LIMIT {
+        if io_in8(PORT_STATUS) & STATUS_OUTPUT_FULL == 0 {
+            break;
+        }
+        io_in8(PORT_DATA);
+    }
+}
+
+fn command(cmd: u8) -> Result<(), &'static str> {
+    wait_sendready()?;
+    io_out8(PORT_STATUS, cmd);
+    Ok(())
+}
+
+fn read_config() -> Result<u8, &'static str> {
+    command(CMD_READ_CONFIG)?;
+    Ok(read_timeout(TIMEOUT_MS).unwrap_or(0))
+}
+
+fn write_config(config: u8) -> Result<(), &'static str> {
+    command(CMD_WRITE_CONFIG)?;
+    wait_sendready()?;
+    io_out8(PORT_DATA, config);
+    Ok(())
+}
+
+//向端口上的设备发送一个字节，不等待应答
+pub fn write(port: Ps2Port, data: u8) -> Result<(), &'static str> {
+    if port == Ps2Port::Second {
+        command(CMD_WRITE_PORT2)?;
+    }
+    wait_sendready()?;
+    io_out8(PORT_DATA, data);
+    Ok(())
+}
+
+//发送并等待ACK，只在初始化时（关中断）使用
+pub fn write_ack(port: Ps2Port, data: u8) -> Result<(), &'static str> {
+    write(port, data)?;
+    match read_timeout(TIMEOUT_MS) {
+        Some(DEV_ACK) => Ok(()),
+        Some(_) => Err("PS/2 device did not acknowledge"),
+        None => Err("PS/2 device timed out"),
+    }
+}
+
+fn reset_device(port: Ps2Port) -> Result<(), &'static str> {
+    write_ack(port, DEV_RESET)?;
+    if read_timeout(RESET_TIMEOUT_MS) != Some(DEV_SELF_TEST_OK) {
+        return Err("PS/2 device self test failed");
+    }
+    //鼠标在自检结果后发送ID
+    read_timeout(TIMEOUT_MS);
+    Ok(())
+}
+
+//根据IDENTIFY的应答判断设备的种类
+//键盘为0xAB加第二个字节，鼠标为一个字节
+//无应答的可能是不支持该命令的旧式AT键盘，只在第一个端口上且复位成功时这样认为
+fn identify(port: Ps2Port, reset_ok: bool) -> Device {
+    if write_ack(port, DEV_DISABLE_SCANNING).is_err() {
+        return Device::None;
+    }
+    if write_ack(port, DEV_IDENTIFY).is_err() {
+        return Device::Unknown;
+    }
+    let device = match read_timeout(TIMEOUT_MS) {
+        None if port == Ps2Port::First && reset_ok => Device::Keyboard,
+        None => Device::Unknown,
+        Some(0x00) => Device::Mouse,
+        Some(0x03) => Device::WheelMouse,
+        Some(0x04) => Device::FiveButtonMouse,
+        Some(0xab) => Device::Keyboard,
+        Some(_) => Device::Unknown,
+    };
+    //丢弃第二个ID字节
+    read_timeout(TIMEOUT_MS);
+    device
+}
+
+fn set_sample_rate(port: Ps2Port, rate: u8) -> Result<(), &'static str> {
+    write_ack(port, DEV_SET_SAMPLE_RATE)?;
+    write_ack(port, rate)
+}
+
+//尝试开启滚轮，成功后再尝试开启第4、5键；不支持的鼠标ID不变
+fn enable_extensions(port: Ps2Port, mut device: Device) -> Device {
+    for (sequence, extended) in [(WHEEL_SEQUENCE, Device::WheelMouse), (FIVE_BUTTON_SEQUENCE, Device::FiveButtonMouse)] {
+        if sequence.iter().any(|&rate| set_sample_rate(port, rate).is_err()) {
+            break;
+        }
+        if identify(port, false) != extended {
+            break;
+        }
+        device = extended;
+    }
+    let _ = set_sample_rate(port, DEFAULT_SAMPLE_RATE);
+    device
+}
+
+fn init_device(port: Ps2Port) -> Device {
+    let reset = reset_device(port);
+    if let Err(e) = reset {
+        serial_println!("[PS/2] {:?} port: {}", port, e);
+    }
+    let mut device = identify(port, reset.is_ok());
+    if device == Device::Mouse {
+        device = enable_extensions(port, device);
+    }
+    //只有第一个端口有扫描码的转换，接在第二个端口的键盘切换到扫描码集1
+    if port == Ps2Port::Second && device == Device::Keyboard {
+        if write_ack(port, DEV_SET_SCANCODE_SET).is_err() || write_ack(port, 1).is_err() {
+            serial_println!("[PS/2] keyboard does not support scancode set 1");
+        }
+    }
+    if device != Device::None && write_ack(port, DEV_ENABLE_SCANNING).is_err() {
+        return Device::Unknown;
+    }
+    device
+}
+
+//初始化控制器并检测两个端口上的设备，需要在关中断、开中断之前调用
+//设备可以缺失，也可以接在另一个端口上
+pub fn init() -> Result<(), &'static str> {
+    command(CMD_DISABLE_PORT1)?;
+    command(CMD_DISABLE_PORT2)?;
+    flush();
+
+    let mut config = read_config()?;
+    config &= !(CONFIG_PORT1_INT | CONFIG_PORT2_INT | CONFIG_TRANSLATION);
+    write_config(config)?;
+
+    command(CMD_SELF_TEST)?;
+    if read_timeout(TIMEOUT_MS) != Some(SELF_TEST_OK) {
+        return Err("PS/2 controller self test failed");
+    }
+    //自检可能会复位控制器
+    write_config(config)?;
+
+    //禁用时第二个端口的时钟关闭，启用后打开的是双端口控制器
+    let mut dual = false;
+    if config & CONFIG_PORT2_CLOCK_OFF != 0 {
+        command(CMD_ENABLE_PORT2)?;
+        dual = read_config()? & CONFIG_PORT2_CLOCK_OFF == 0;
+        command(CMD_DISABLE_PORT2)?;
+    }
+
+    command(CMD_TEST_PORT1)?;
+    let port1 = read_timeout(TIMEOUT_MS) == Some(PORT_TEST_OK);
+    let port2 = dual && {
+        command(CMD_TEST_PORT2)?;
+        read_timeout(TIMEOUT_MS) == Some(PORT_TEST_OK)
+    };
+    if !port1 && !port2 {
+        return Err("No working PS/2 port");
+    }
+
+    let mut devices = [Device::None; 2];
+    if port1 {
+        command(CMD_ENABLE_PORT1)?;
+        devices[0] = init_device(Ps2Port::First);
+    }
+    if port2 {
+        command(CMD_ENABLE_PORT2)?;
+        devices[1] = init_device(Ps2Port::Second);
+    }
+    flush();
+
+    config = read_config()?;
+    config &= !(CONFIG_PORT1_CLOCK_OFF | CONFIG_PORT2_CLOCK_OFF);
+    if devices[0] != Device::None {
+        config |= CONFIG_PORT1_INT;
+    }
+    if devices[1] != Device::None {
+        config |= CONFIG_PORT2_INT;
+    }
+    //接在第一个端口的是鼠标时不能转换
+    if devices[0] == Device::Keyboard {
+        config |= CONFIG_TRANSLATION;
+    }
+    write_config(config)?;
+    for (slot, device) in DEVICES.iter().zip(devices) {
+        slot.store(device as u8, Ordering::Release);
+    }
+    if let Some(&mouse) = devices.iter().find(|device| device.is_mouse()) {
+        mouse::set_device(mouse);
+    }
+    Ok(())
+}
+
+pub fn device(port: Ps2Port) -> Device {
+    Device::from_u8(DEVICES[port.index()].load(Ordering::Acquire))
+}
+
+//接有指定设备的端口
+pub fn find(pred: fn(Device) -> bool) -> Option<Ps2Port> {
+    [Ps2Port::First, Ps2Port::Second].iter().copied().find(|&port| pred(device(port)))
+}
+
+//IRQ1和IRQ12的中断处理程序读出数据后调用，按端口上的设备分发
+pub fn receive(port: Ps2Port, data: u8) {
+    match device(port) {
+        //队列满时丢弃，由overruns计数
+        Device::Keyboard => { let _ = EVENT_FIFO.put(Event::Key(data)); }
+        Device::Mouse | Device::WheelMouse | Device::FiveButtonMouse => mouse::receive(data),
+        Device::None | Device::Unknown => {}
+    }
+}
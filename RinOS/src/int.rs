@@ -2,18 +2,23 @@ use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 use crate::{asm, serial_print, serial_println};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
-use crate::{keyboard, mouse, gdt, timer};
+use crate::{apic, keyboard, mouse, gdt, rtc, timer};
 use x86_64::structures::idt::PageFaultErrorCode;
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+pub const PIC0_IMR: u16 = 0x21;
+pub const PIC1_IMR: u16 = 0xa1;
+//LAPIC的伪中断
+pub const SPURIOUS_VECTOR: u8 = 0xff;
 
 #[derive(Debug, Clone, Copy)]
 #[repr(u8)]
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard = PIC_1_OFFSET + 1,
-    Mouse = PIC_1_OFFSET + 12
+    Mouse = PIC_1_OFFSET + 12,
+    Rtc = PIC_2_OFFSET
 }
 
 impl InterruptIndex {
@@ -24,12 +29,44 @@ impl InterruptIndex {
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    //ISA的irq号
+    pub fn irq(self) -> u8 {
+        self.as_u8() - PIC_1_OFFSET
+    }
 }
 
 pub static PICS: spin::Mutex<ChainedPics> = spin::Mutex::new(unsafe {
     ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET)
 });
 
+//根据启动时选择的中断控制器通知中断处理结束
+pub fn notify_end_of_interrupt(index: InterruptIndex) {
+    if apic::enabled() {
+        apic::end_of_interrupt();
+    } else {
+        unsafe {
+            PICS.lock().notify_end_of_interrupt(index.as_u8());
+        }
+    }
+}
+
+//允许默认被屏蔽的irq，根据中断控制器设定PIC的IMR或IO-APIC
+pub fn enable_irq(index: InterruptIndex) {
+    if apic::enabled() {
+        apic::route_irq(index.irq(), index.as_u8());
+        return;
+    }
+    let irq = index.irq();
+    if irq < 8 {
+        asm::io_out8(PIC0_IMR, asm::io_in8(PIC0_IMR) & !(1 << irq));
+    } else {
+        //从PIC连接在主PIC的IRQ2上
+        asm::io_out8(PIC1_IMR, asm::io_in8(PIC1_IMR) & !(1 << (irq - 8)));
+        asm::io_out8(PIC0_IMR, asm::io_in8(PIC0_IMR) & !(1 << 2));
+    }
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
@@ -41,6 +78,10 @@ lazy_static! {
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer::timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard::keyboard_interrupt_handler);
         idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse::mouse_interrupt_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc::rtc_interrupt_handler);
+        //切换到APIC时可能残留的PIC伪中断（IRQ7）
+        idt[PIC_1_OFFSET as usize + 7].set_handler_fn(spurious_interrupt_handler);
+        idt[SPURIOUS_VECTOR as usize].set_handler_fn(spurious_interrupt_handler);
         idt
     };
 }
@@ -53,6 +94,9 @@ extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
     serial_print!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
 }
 
+//伪中断不需要EOI
+extern "x86-interrupt" fn spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> ! {
     panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
 }
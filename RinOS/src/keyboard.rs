@@ -1,22 +1,440 @@
 use lazy_static::lazy_static;
 use x86_64::structures::idt::InterruptStackFrame;
 use crate::asm;
-use crate::int::{InterruptIndex, PICS};
-use pc_keyboard::{layouts, HandleControl, Keyboard, ScancodeSet1};
+use crate::int::{self, InterruptIndex};
+use alloc::boxed::Box;
+use pc_keyboard::{layouts, DecodedKey, Error, HandleControl, KeyCode, KeyState, Keyboard, KeyboardLayout, ScancodeSet1};
+use pc_keyboard::KeyEvent as ScanEvent;
 use spin::Mutex;
-use crate::fifo::Fifo;
+use x86_64::instructions::interrupts;
+use crate::event::EVENT_FIFO;
+use crate::i8042::{self, Device, Ps2Port};
+use core::str;
+use crate::{fw_cfg, pit, serial_println, timer};
+use crate::timer::TimerHandle;
+
+const PORT_KEYDAT: u16 = 0x60;
+
+//发送给键盘的命令
+pub const KEYCMD_LED: u8 = 0xed;
+pub const KEYCMD_TYPEMATIC: u8 = 0xf3;
+//键盘的应答
+const KEY_ACK: u8 = 0xfa;
+const KEY_RESEND: u8 = 0xfe;
+
+const KEYCMD_SIZE: usize = 16;
+const KEYCMD_RETRIES: u8 = 3;
+//键盘不应答时放弃该命令
+const KEYCMD_TIMEOUT_MS: u64 = 500;
+//发送命令时设定的定时器写入EVENT_FIFO的data
+pub const KEYCMD_TIMER: u32 = 4;
+
+//LED的位，与0xED命令的数据相同
+pub const LED_SCROLL: u8 = 0x01;
+pub const LED_NUM: u8 = 0x02;
+pub const LED_CAPS: u8 = 0x04;
+//与pc_keyboard的初始状态相同，Num Lock为开
+pub const DEFAULT_LOCKS: u8 = LED_NUM;
+
+//0xF3的数据：bit0～4为速率（0为30字/秒，31为2字/秒），bit5～6为延迟（250ms×(n+1)）
+pub const DEFAULT_TYPEMATIC_RATE: u8 = 0x0b;
+pub const DEFAULT_TYPEMATIC_DELAY: u8 = 1;
+
+//启动时的键盘布局，优先使用QEMU的fw_cfg文件KEYMAP_FILE（例如-fw_cfg name=opt/rinos/keymap,string=de）
+//没有时使用编译时的环境变量RINOS_KEYMAP，运行中用控制台的keymap命令切换
+const KEYMAP_FILE: &str = "opt/rinos/keymap";
+const DEFAULT_LAYOUT: Option<&str> = option_env!("RINOS_KEYMAP");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    De105,
+    Jis109,
+    Azerty,
+    Dvorak104,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 6] = [
+        Layout::Us104,
+        Layout::Uk105,
+        Layout::De105,
+        Layout::Jis109,
+        Layout::Azerty,
+        Layout::Dvorak104,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us104 => "us",
+            Layout::Uk105 => "uk",
+            Layout::De105 => "de",
+            Layout::Jis109 => "jis",
+            Layout::Azerty => "azerty",
+            Layout::Dvorak104 => "dvorak",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.iter().copied().find(|l| l.name().eq_ignore_ascii_case(name))
+    }
+
+    //ALL中的下一个布局
+    pub fn next(self) -> Layout {
+        let i = Layout::ALL.iter().position(|&l| l == self).unwrap_or(0);
+        Layout::ALL[(i + 1) % Layout::ALL.len()]
+    }
+}
+
+//pc_keyboard的布局是类型参数，通过该trait在运行时切换
+pub trait KeyDecoder: Send {
+    fn add_byte(&mut self, byte: u8) -> Result<Option<ScanEvent>, Error>;
+    fn process_keyevent(&mut self, event: ScanEvent) -> Option<DecodedKey>;
+}
+
+impl<L: KeyboardLayout + Send> KeyDecoder for Keyboard<L, ScancodeSet1> {
+    fn add_byte(&mut self, byte: u8) -> Result<Option<ScanEvent>, Error> {
+        Keyboard::add_byte(self, byte)
+    }
+
+    fn process_keyevent(&mut self, event: ScanEvent) -> Option<DecodedKey> {
+        Keyboard::process_keyevent(self, event)
+    }
+}
+
+//KeyEvent::modifiers的位，锁定键的状态与LED相同
+pub const MOD_SHIFT: u8 = 0x01;
+pub const MOD_CTRL: u8 = 0x02;
+pub const MOD_ALT: u8 = 0x04;
+pub const MOD_GUI: u8 = 0x08;
+pub const MOD_SCROLL_LOCK: u8 = LED_SCROLL << 4;
+pub const MOD_NUM_LOCK: u8 = LED_NUM << 4;
+pub const MOD_CAPS_LOCK: u8 = LED_CAPS << 4;
+
+//按下中的修饰键，左右分别记录
+const HELD_LSHIFT: u8 = 0x01;
+const HELD_RSHIFT: u8 = 0x02;
+const HELD_LCTRL: u8 = 0x04;
+const HELD_RCTRL: u8 = 0x08;
+const HELD_LALT: u8 = 0x10;
+const HELD_RALT: u8 = 0x20;
+const HELD_LGUI: u8 = 0x40;
+const HELD_RGUI: u8 = 0x80;
+const HELD_SHIFT: u8 = HELD_LSHIFT | HELD_RSHIFT;
+const HELD_CTRL: u8 = HELD_LCTRL | HELD_RCTRL;
+const HELD_ALT: u8 = HELD_LALT | HELD_RALT;
+const HELD_GUI: u8 = HELD_LGUI | HELD_RGUI;
+
+//交给应用程序的按键事件，按下和松开都会产生
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub pressed: bool,
+    pub modifiers: u8, //MOD_SHIFT等的组合，包含该键本身
+    pub ch: Option<char>, //按下时按布局解码出的字符
+}
+
+impl KeyEvent {
+    pub fn shift(&self) -> bool {
+        self.modifiers & MOD_SHIFT != 0
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.modifiers & MOD_CTRL != 0
+    }
+
+    pub fn alt(&self) -> bool {
+        self.modifiers & MOD_ALT != 0
+    }
+}
+
+pub struct LayoutKeyboard {
+    layout: Layout,
+    decoder: Box<dyn KeyDecoder>,
+    locks: u8, //LED_CAPS等的组合
+    held: u8, //HELD_SHIFT等
+}
+
+impl LayoutKeyboard {
+    pub fn new(layout: Layout) -> LayoutKeyboard {
+        let decoder: Box<dyn KeyDecoder> = match layout {
+            Layout::Us104 => Box::new(Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)),
+            Layout::Uk105 => Box::new(Keyboard::new(layouts::Uk105Key, ScancodeSet1, HandleControl::Ignore)),
+            Layout::De105 => Box::new(Keyboard::new(layouts::De105Key, ScancodeSet1, HandleControl::Ignore)),
+            Layout::Jis109 => Box::new(Keyboard::new(layouts::Jis109Key, ScancodeSet1, HandleControl::Ignore)),
+            Layout::Azerty => Box::new(Keyboard::new(layouts::Azerty, ScancodeSet1, HandleControl::Ignore)),
+            Layout::Dvorak104 => Box::new(Keyboard::new(layouts::Dvorak104Key, ScancodeSet1, HandleControl::Ignore)),
+        };
+        LayoutKeyboard { layout, decoder, locks: DEFAULT_LOCKS, held: 0 }
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    pub fn locks(&self) -> u8 {
+        self.locks
+    }
+
+    pub fn modifiers(&self) -> u8 {
+        let mut modifiers = self.locks << 4;
+        for (held, modifier) in [(HELD_SHIFT, MOD_SHIFT), (HELD_CTRL, MOD_CTRL), (HELD_ALT, MOD_ALT), (HELD_GUI, MOD_GUI)] {
+            if self.held & held != 0 {
+                modifiers |= modifier;
+            }
+        }
+        modifiers
+    }
+
+    //输入一个扫描码，一个按键的序列结束时返回事件
+    pub fn decode(&mut self, byte: u8) -> Option<KeyEvent> {
+        let event = self.decoder.add_byte(byte).ok()??;
+        let code = event.code;
+        let pressed = event.state == KeyState::Down;
+        let held = match code {
+            KeyCode::ShiftLeft => HELD_LSHIFT,
+            KeyCode::ShiftRight => HELD_RSHIFT,
+            KeyCode::ControlLeft => HELD_LCTRL,
+            KeyCode::ControlRight => HELD_RCTRL,
+            KeyCode::AltLeft => HELD_LALT,
+            KeyCode::AltRight => HELD_RALT,
+            KeyCode::WindowsLeft => HELD_LGUI,
+            KeyCode::WindowsRight => HELD_RGUI,
+            _ => 0,
+        };
+        if pressed {
+            self.held |= held;
+        } else {
+            self.held &= !held;
+        }
+        let ch = match self.process_keyevent(event) {
+            Some(DecodedKey::Unicode(c)) => Some(c),
+            _ => None,
+        };
+        Some(KeyEvent { code, pressed, modifiers: self.modifiers(), ch })
+    }
+
+    //pc_keyboard在按下Caps Lock和Num Lock时切换状态，这里同样切换并更新LED
+    fn process_keyevent(&mut self, event: ScanEvent) -> Option<DecodedKey> {
+        if event.state == KeyState::Down {
+            let led = match event.code {
+                KeyCode::CapsLock => LED_CAPS,
+                KeyCode::NumpadLock => LED_NUM,
+                KeyCode::ScrollLock => LED_SCROLL,
+                _ => 0,
+            };
+            if led != 0 {
+                self.locks ^= led;
+                let _ = set_leds(self.locks);
+            }
+        }
+        self.decoder.process_keyevent(event)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Command {
+    cmd: u8,
+    data: u8,
+}
+
+//发送给键盘的命令队列
+//一次只发送一个字节，收到ACK后再发送下一个，收到RESEND时重发
+pub struct KeyCmd {
+    queue: [Command; KEYCMD_SIZE],
+    head: usize,
+    len: usize,
+    current: Option<Command>,
+    data_sent: bool, //current的命令字节已被应答，正在发送数据
+    retries: u8,
+    sent_at: u64,
+    timer: Option<TimerHandle>, //等待应答的超时，在init中分配
+}
+
+impl KeyCmd {
+    pub const fn new() -> KeyCmd {
+        KeyCmd {
+            queue: [Command { cmd: 0, data: 0 }; KEYCMD_SIZE],
+            head: 0,
+            len: 0,
+            current: None,
+            data_sent: false,
+            retries: 0,
+            sent_at: 0,
+            timer: None,
+        }
+    }
+
+    pub fn push(&mut self, cmd: u8, data: u8) -> Result<(), &'static str> {
+        //同一命令尚未发送时只保留最新的数据
+        for i in 0..self.len {
+            let command = &mut self.queue[(self.head + i) % KEYCMD_SIZE];
+            if command.cmd == cmd {
+                command.data = data;
+                return Ok(());
+            }
+        }
+        if self.len == KEYCMD_SIZE {
+            return Err("Keyboard command queue is full");
+        }
+        self.queue[(self.head + self.len) % KEYCMD_SIZE] = Command { cmd, data };
+        self.len += 1;
+        self.kick();
+        Ok(())
+    }
+
+    //没有等待应答的命令时发送下一个，等待超时的命令被丢弃
+    fn kick(&mut self) {
+        if self.current.is_some() {
+            let timeout = pit::ms_to_ticks(KEYCMD_TIMEOUT_MS);
+            if timer::uptime().wrapping_sub(self.sent_at) < timeout {
+                return;
+            }
+            self.current = None;
+        }
+        if self.len == 0 {
+            return;
+        }
+        let command = self.queue[self.head];
+        self.head = (self.head + 1) % KEYCMD_SIZE;
+        self.len -= 1;
+        self.current = Some(command);
+        self.data_sent = false;
+        self.retries = 0;
+        self.send(command.cmd);
+    }
+
+    fn send(&mut self, byte: u8) {
+        if let Some(port) = i8042::find(|device| device == Device::Keyboard) {
+            //写入失败时等待超时
+            let _ = i8042::write(port, byte);
+        }
+        self.sent_at = timer::uptime();
+        if let Some(timer) = &self.timer {
+            timer.set_time_ms(KEYCMD_TIMEOUT_MS);
+        }
+    }
+
+    //当前的命令结束，发送下一个
+    fn finish(&mut self) {
+        self.current = None;
+        if let Some(timer) = &self.timer {
+            timer.cancel();
+        }
+        self.kick();
+    }
+
+    //处理键盘发来的字节，是命令的应答时返回true
+    pub fn reply(&mut self, byte: u8) -> bool {
+        let command = match self.current {
+            Some(command) => command,
+            None => return false,
+        };
+        match byte {
+            KEY_ACK => {
+                if self.data_sent {
+                    self.finish();
+                } else {
+                    self.data_sent = true;
+                    self.retries = 0;
+                    self.send(command.data);
+                }
+                true
+            }
+            KEY_RESEND => {
+                if self.retries < KEYCMD_RETRIES {
+                    self.retries += 1;
+                    self.send(if self.data_sent { command.data } else { command.cmd });
+                } else {
+                    self.finish();
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+pub static KEYCMD: Mutex<KeyCmd> = Mutex::new(KeyCmd::new());
+
+//主任务收到的字节先交给命令队列，是应答时不再解码
+pub fn handle_reply(byte: u8) -> bool {
+    interrupts::without_interrupts(|| KEYCMD.lock().reply(byte))
+}
+
+//主任务收到KEYCMD_TIMER时调用，键盘没有应答时继续发送队列中的命令
+pub fn poll_commands() {
+    interrupts::without_interrupts(|| KEYCMD.lock().kick());
+}
+
+pub fn set_leds(locks: u8) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| KEYCMD.lock().push(KEYCMD_LED, locks & 0x07))
+}
+
+//rate为0～31（越小越快），delay为0～3
+pub fn set_typematic(rate: u8, delay: u8) -> Result<(), &'static str> {
+    if rate > 0x1f || delay > 3 {
+        return Err("Invalid typematic rate or delay");
+    }
+    interrupts::without_interrupts(|| KEYCMD.lock().push(KEYCMD_TYPEMATIC, delay << 5 | rate))
+}
+
+pub fn locks() -> u8 {
+    interrupts::without_interrupts(|| KEYBOARD.lock().locks())
+}
+
+//使LED与锁定状态一致，设定默认的重复速率
+pub fn init() {
+    if i8042::find(|device| device == Device::Keyboard).is_none() {
+        return;
+    }
+    let timer = TimerHandle::new(&EVENT_FIFO, KEYCMD_TIMER);
+    interrupts::without_interrupts(|| KEYCMD.lock().timer = timer);
+    let _ = set_leds(locks());
+    let _ = set_typematic(DEFAULT_TYPEMATIC_RATE, DEFAULT_TYPEMATIC_DELAY);
+}
 
 lazy_static! {
-    pub static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-        Mutex::new(Keyboard::new(layouts::Us104Key, ScancodeSet1,HandleControl::Ignore));
-    pub static ref KEYBUF: Mutex<Fifo> = Mutex::new(Fifo::new(32));
+    //第一次使用时分配，必须在堆初始化之后
+    pub static ref KEYBOARD: Mutex<LayoutKeyboard> = {
+        let layout = boot_layout().unwrap_or(Layout::Us104);
+        Mutex::new(LayoutKeyboard::new(layout))
+    };
+}
+
+//启动时指定的布局，名称无效时忽略
+fn boot_layout() -> Option<Layout> {
+    let mut buf = [0; 16];
+    let from_boot = fw_cfg::read_file(KEYMAP_FILE, &mut buf)
+        .and_then(|len| str::from_utf8(&buf[..len]).ok())
+        .map(|name| name.trim_matches(|c: char| c == '\0' || c.is_ascii_whitespace()));
+    if let Some(name) = from_boot {
+        match Layout::from_name(name) {
+            Some(layout) => return Some(layout),
+            None => serial_println!("[keyboard] unknown keymap {:?} in {}", name, KEYMAP_FILE),
+        }
+    }
+    DEFAULT_LAYOUT.and_then(Layout::from_name)
 }
 
+//切换布局，正在按下的修饰键和锁定状态会被清除
+//主任务的优先级更高，因此其他任务持有锁时不能被切换出去
+pub fn set_layout(layout: Layout) {
+    let keyboard = LayoutKeyboard::new(layout);
+    let old = interrupts::without_interrupts(|| core::mem::replace(&mut *KEYBOARD.lock(), keyboard));
+    drop(old);
+    let _ = set_leds(DEFAULT_LOCKS);
+}
+
+pub fn layout() -> Layout {
+    interrupts::without_interrupts(|| KEYBOARD.lock().layout())
+}
+
+//IRQ1，数据来自第一个端口，不一定是键盘
 pub extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    let scancode = asm::io_in8(0x60);
-    KEYBUF.lock().put(scancode).unwrap();
+    let data = asm::io_in8(PORT_KEYDAT);
+    i8042::receive(Ps2Port::First, data);
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
-    }
+    int::notify_end_of_interrupt(InterruptIndex::Keyboard);
 }
@@ -11,12 +11,12 @@ use crate::{SCREEN_HEIGHT, SCREEN_WIDTH, serial_print, serial_println};
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Layer {
     buf: usize,
-    x0: usize,
-    y0: usize,
-    xsize: usize,
-    ysize: usize,
+    pub x0: usize,
+    pub y0: usize,
+    pub xsize: usize,
+    pub ysize: usize,
     is_used: bool,
-    z: Option<usize>,
+    pub z: Option<usize>,
     transparent: Option<Color16>
 }
 
@@ -201,14 +201,12 @@ impl LayerCtl {
                 }
             } else {
                 if let Some(z) = oz {
-                    let z_max = if let Some(z_max) = self.z_max {
-                        z_max
-                    } else {
-                        0
-                    };
-                    for h in z..z_max {
-                        self.layers[h + 1] = self.layers[h];
-                        self.layer_data[self.layers[h + 1]].z = Some(h + 1);
+                    //从上往下移动，为新图层腾出位置
+                    if let Some(z_max) = self.z_max {
+                        for h in (z..=z_max).rev() {
+                            self.layers[h + 1] = self.layers[h];
+                            self.layer_data[self.layers[h + 1]].z = Some(h + 1);
+                        }
                     }
                     self.layers[z] = layer_index;
                     if let Some(z_max) = self.z_max {
@@ -248,23 +246,28 @@ impl LayerCtl {
         }
     }
 
-    pub fn slide_by_diff(&mut self, layer_index: usize, dx: isize, dy: isize, width: isize, height: isize) {
-        let layer = self.layer_data[layer_index];
-        let mut new_x = layer.x0 as isize + dx;
-        let mut new_y = layer.y0 as isize + dy;
-        let x_max = SCREEN_WIDTH as isize - 1;
-        let y_max = SCREEN_HEIGHT as isize - 1;
-        if new_x < 0 {
-            new_x = 0;
-        } else if new_x > x_max {
-            new_x = x_max;
+    //(x, y)处显示的图层，被exclude（鼠标图层）遮住时返回其下面的图层
+    pub fn layer_at(&self, x: usize, y: usize, exclude: usize) -> Option<usize> {
+        if x >= SCREEN_WIDTH || y >= SCREEN_HEIGHT {
+            return None;
         }
-        if new_y < 0 {
-            new_y = 0;
-        } else if new_y > y_max {
-            new_y = y_max;
+        let si = MAP.lock()[y * SCREEN_WIDTH + x] as usize;
+        if si != exclude {
+            return Some(si);
         }
-        self.slide(layer_index, new_x as usize, new_y as usize);
+        for h in (0..=self.z_max?).rev() {
+            let si = self.layers[h];
+            let layer = &self.layer_data[si];
+            if si == exclude || x < layer.x0 || y < layer.y0 || x >= layer.x0 + layer.xsize || y >= layer.y0 + layer.ysize {
+                continue;
+            }
+            let buf = layer.buf as *const Color16;
+            let c = unsafe { *buf.add((y - layer.y0) * layer.xsize + (x - layer.x0)) };
+            if !layer.transparent.contains(&c) {
+                return Some(si);
+            }
+        }
+        None
     }
 
     pub fn free(&mut self, layer_index: usize) {
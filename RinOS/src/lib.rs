@@ -2,11 +2,41 @@
 #![feature(alloc_error_handler)]
 extern crate alloc;
 pub mod allocator;
+use alloc::alloc::{GlobalAlloc, Layout};
+use core::ops::Deref;
 use linked_list_allocator::LockedHeap;
+use x86_64::instructions::interrupts;
 use core::panic;
 
+//分配时关中断，使持有堆的锁的任务不会被切换出去或被强制结束
+pub struct Allocator(LockedHeap);
+
+impl Allocator {
+    pub const fn empty() -> Allocator {
+        Allocator(LockedHeap::empty())
+    }
+}
+
+impl Deref for Allocator {
+    type Target = LockedHeap;
+
+    fn deref(&self) -> &LockedHeap {
+        &self.0
+    }
+}
+
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        interrupts::without_interrupts(|| self.0.alloc(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        interrupts::without_interrupts(|| self.0.dealloc(ptr, layout))
+    }
+}
+
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Allocator = Allocator::empty();
 
 #[alloc_error_handler]
 fn alloc_error_handler(layout: alloc::alloc::Layout) -> ! {
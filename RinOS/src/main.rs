@@ -2,6 +2,9 @@
 #![feature(option_result_contains)]
 #![no_std] // 不链接 Rust 标准库
 #![no_main] // 禁用所有 Rust 层级的入口点
+#![feature(custom_test_frameworks)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 
 mod asm;
 mod vga;
@@ -10,23 +13,37 @@ mod int;
 mod serial;
 mod gdt;
 mod fifo;
+mod event;
+mod ring;
+mod i8042;
 mod keyboard;
 mod mouse;
 mod memory;
 mod layer;
 mod window;
 mod timer;
+mod task;
+mod pit;
+mod apic;
+mod rtc;
+mod fw_cfg;
+mod tsc;
+mod ansi;
+mod console;
+mod compositor;
+mod pointer;
 use x86_64::instructions::interrupts;
 
 extern crate alloc;
 
 use core::panic::PanicInfo;
+use core::time::Duration;
 use ::vga::colors::Color16;
-use pc_keyboard::DecodedKey;
 use crate::asm::{io_cli, io_hlt, io_sti, io_stihlt};
-use crate::keyboard::{KEYBOARD, KEYBUF};
+use crate::keyboard::KEYBOARD;
+use pc_keyboard::KeyCode;
 use crate::mouse::{MOUSE_CURSOR_WIDTH, MOUSE_CURSOR_HEIGHT, MOUSE_CURSOR};
-use crate::vga::{VGA, SCREEN_WIDTH, SCREEN_HEIGHT, LineWriter, update_mouse_cursor, boxfill};
+use crate::vga::{VGA, SCREEN_WIDTH, SCREEN_HEIGHT, LayerWriter, LineWriter, boxfill};
 use bootloader::{BootInfo, entry_point};
 use x86_64::VirtAddr;
 use crate::memory::BootInfoFrameAllocator;
@@ -35,11 +52,11 @@ use core::borrow::BorrowMut;
 use core::slice::SliceIndex;
 use ::vga::writers::GraphicsWriter;
 use lazy_static::lazy_static;
-use ps2_mouse::MouseState;
 use crate::layer::{bg_layer_index, LAYERCTL, mouse_layer_index, win_layer_index};
 use spin::Mutex;
-use crate::fifo::Fifo;
-use crate::timer::TIMER_CTL;
+use crate::console::CONSOLE_FIFO;
+use crate::event::{Event, EVENT_FIFO};
+use crate::timer::TimerHandle;
 
 entry_point!(kernel_main);
 
@@ -50,17 +67,38 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     int::init_idt();
     gdt::init_gdt();
     unsafe { int::PICS.lock().initialize(); }
-    mouse::enable_mouse();
+    pit::init(pit::DEFAULT_FREQUENCY);
+    match tsc::init() {
+        Ok(hz) => serial_println!("[TSC] {} kHz, invariant: {}", hz / 1000, tsc::invariant()),
+        Err(e) => serial_println!("[TSC] {}", e),
+    }
+    match i8042::init() {
+        Ok(()) => serial_println!(
+            "[PS/2] port 1: {}, port 2: {}",
+            i8042::device(i8042::Ps2Port::First).name(),
+            i8042::device(i8042::Ps2Port::Second).name()
+        ),
+        Err(e) => serial_println!("[PS/2] {}", e),
+    }
     io_sti();
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     let mut mapper = unsafe { memory::init(phys_mem_offset)};
     let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
     allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    //支持时从8259 PIC切换到APIC
+    match apic::init(&mut mapper, &mut frame_allocator) {
+        Ok(()) => serial_println!("[APIC] enabled"),
+        Err(e) => serial_println!("[APIC] {}, using 8259 PIC", e),
+    }
+    //APIC初始化时会清空键盘的缓冲区，之后再发送命令
+    keyboard::init();
+
+    #[cfg(test)]
+    test_main();
 
     let mut mouse: Vec<Color16> = vec![Color16::Black; MOUSE_CURSOR_WIDTH * MOUSE_CURSOR_HEIGHT];
     let mut background: Vec<Color16> = vec![Color16::Black; SCREEN_WIDTH * SCREEN_HEIGHT];
-    let mut window: Vec<Color16> = vec![Color16::Black; 160 * 52];
 
     //初始化鼠标指针
     for j in 0..MOUSE_CURSOR_HEIGHT {
@@ -76,7 +114,6 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     VGA.lock().set_mode();
     *bg_layer_index.lock() = LAYERCTL.lock().alloc().unwrap();
     *mouse_layer_index.lock() = LAYERCTL.lock().alloc().unwrap();
-    *win_layer_index.lock() = LAYERCTL.lock().alloc().unwrap();
     LAYERCTL.lock().set_buf(
         *bg_layer_index.lock(),
         &mut background,
@@ -91,80 +128,121 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
         MOUSE_CURSOR_HEIGHT,
         Some(Color16::Cyan)
     );
-    LAYERCTL.lock().set_buf(
-        *win_layer_index.lock(),
-        &mut window,
-        160,
-        52,
-        None
-    );
     vga::init_screen(&mut background);
-    window::make_window(&mut window, 160, 52, "counter");
     // let mut writer = LineWriter::new(Color16::Black, 24, 28, 160, 68);
     // writer.write_str("Welcome to\nRinOS.", window.borrow_mut());
     LAYERCTL.lock().slide(*mouse_layer_index.lock(), (640 - 16) / 2, (480 -28 - 16) / 2);
-    LAYERCTL.lock().slide(*win_layer_index.lock(), 80, 72);
     LAYERCTL.lock().up_down(*bg_layer_index.lock(), Some(0));
-    LAYERCTL.lock().up_down(*win_layer_index.lock(), Some(1));
-    LAYERCTL.lock().up_down(*mouse_layer_index.lock(), Some(2));
-
-    let mut timer_buf1 = Fifo::new(8);
-    let mut timer_buf2 = Fifo::new(8);
-    let mut timer_buf3 = Fifo::new(8);
-    let mut timer_id1 = TIMER_CTL.lock().alloc().unwrap();
-    TIMER_CTL.lock().init_timer(timer_id1, &mut timer_buf1, 1);
-    TIMER_CTL.lock().set_time(timer_id1, 100000);
-    let mut timer_id2 = TIMER_CTL.lock().alloc().unwrap();
-    TIMER_CTL.lock().init_timer(timer_id2, &mut timer_buf2, 1);
-    TIMER_CTL.lock().set_time(timer_id2, 300);
-    let mut timer_id3 = TIMER_CTL.lock().alloc().unwrap();
-    TIMER_CTL.lock().init_timer(timer_id3, &mut timer_buf3, 1);
-    TIMER_CTL.lock().set_time(timer_id3, 50);
+    LAYERCTL.lock().up_down(*mouse_layer_index.lock(), Some(1));
+
+    //主任务处理键盘、鼠标和定时器的事件，队列为空时休眠
+    let task_a = task::init();
+    task::run(task_a, Some(1), Some(2)).unwrap();
+    EVENT_FIFO.set_task(Some(task_a));
+    //鼠标由合成任务处理，与主任务同一level
+    task::spawn(compositor::compositor_task, 1, 2).unwrap();
+    task::spawn(counter_task, 2, 2).unwrap();
+    task::spawn(console::console_task, 2, 2).unwrap();
+
+    //所有定时器写入同一个队列，用data区分
+    let timer1 = TimerHandle::new(&EVENT_FIFO, 10).unwrap();
+    timer1.set_timeout(Duration::from_secs(1000));
+    let timer2 = TimerHandle::new(&EVENT_FIFO, 3).unwrap();
+    timer2.set_timeout(Duration::from_secs(3));
+    let timer3 = TimerHandle::new(&EVENT_FIFO, 1).unwrap();
+    timer3.set_periodic_ms(500);
+    let mut blink = false;
+    //每秒读一次RTC，分钟变化时重绘时间显示区
+    let clock_timer = TimerHandle::new(&EVENT_FIFO, 2).unwrap();
+    clock_timer.set_periodic_ms(1000);
+    let mut clock = None;
+    //定期结束没有响应关闭请求的任务
+    let reap_timer = TimerHandle::new(&EVENT_FIFO, 5).unwrap();
+    reap_timer.set_periodic_ms(1000);
 
     loop {
-        io_cli();
-        if let Some(t) = TIMER_CTL.try_lock() {
-            boxfill(window.borrow_mut(), Color16::LightGrey, 40, 28, 119, 43, 160);
-            let mut writer = LineWriter::new(Color16::Black, 40, 28, 160, 52);
-            writer.write_str(&format!("{:>010}", t.count), window.borrow_mut());
-            LAYERCTL.lock().refresh(*win_layer_index.lock(), 40, 28, 120, 44);
-        }
-        if KEYBUF.lock().status() != 0 {
-            let scancode = KEYBUF.lock().get().unwrap();
-            let mut kbd = KEYBOARD.lock();
-            if let Ok(Some(key_event)) = kbd.add_byte(scancode) {
-                if let Some(key) = kbd.process_keyevent(key_event) {
-                    match key {
-                        DecodedKey::Unicode(chr) => serial_println!("[KEYBUF]{}", chr),
-                        DecodedKey::RawKey(key) => serial_println!("[KEYBUF]{:?}", key)
+        match EVENT_FIFO.get_blocking() {
+            Event::Key(scancode) => {
+                //LED等命令的应答
+                if keyboard::handle_reply(scancode) {
+                    continue;
+                }
+                let event = KEYBOARD.lock().decode(scancode);
+                if let Some(event) = event {
+                    match event.code {
+                        //Shift+PageUp/PageDown翻阅控制台的历史
+                        KeyCode::PageUp if event.pressed && event.shift() => {
+                            let _ = CONSOLE_FIFO.put(Event::User(console::MSG_PAGE_UP));
+                        }
+                        KeyCode::PageDown if event.pressed && event.shift() => {
+                            let _ = CONSOLE_FIFO.put(Event::User(console::MSG_PAGE_DOWN));
+                        }
+                        //Shift+F12依次切换键盘布局
+                        KeyCode::F12 if event.pressed && event.shift() => {
+                            let layout = keyboard::layout().next();
+                            keyboard::set_layout(layout);
+                            serial_println!("keymap: {}", layout.name());
+                        }
+                        //键盘输入交给控制台，松开也发送
+                        _ => {
+                            let _ = CONSOLE_FIFO.put(Event::Input(event));
+                        }
                     }
                 }
             }
-        }
-        if timer_buf1.status() != 0 {
-            let _ = timer_buf1.get().unwrap();
-            serial_println!("1000[sec]");
-            timer_buf1 = Fifo::new(8);
-        }
-        if timer_buf2.status() != 0 {
-            let _ = timer_buf2.get().unwrap();
-            serial_println!("3[sec]");
-            timer_buf2 = Fifo::new(8);
-        }
-        if timer_buf3.status() != 0 {
-            let i = timer_buf3.get().unwrap();
-            if i != 0 {
-                TIMER_CTL.lock().init_timer(timer_id3, &mut timer_buf3, 0);
-                serial_println!(".");
-            } else {
-                TIMER_CTL.lock().init_timer(timer_id3, &mut timer_buf3, 1);
-                serial_println!("-");
+            Event::Timer(10) => serial_println!("1000[sec]"),
+            Event::Timer(3) => serial_println!("3[sec]"),
+            Event::Timer(1) => {
+                blink = !blink;
+                if blink {
+                    serial_println!(".");
+                } else {
+                    serial_println!("-");
+                }
             }
-            TIMER_CTL.lock().set_time(timer_id3, 50);
-            io_sti();
+            Event::Timer(2) => {
+                let now = rtc::read();
+                if clock != Some((now.hour, now.minute)) {
+                    clock = Some((now.hour, now.minute));
+                    let bg_index = *bg_layer_index.lock();
+                    let mut writer = LayerWriter::new(&mut background, bg_index, Color16::Black, vga::CLOCK_X + 1, vga::CLOCK_Y + 2, SCREEN_WIDTH, SCREEN_HEIGHT);
+                    writer.set_background(Some(Color16::LightGrey));
+                    window_print!(&mut writer, "{:02}:{:02}", now.hour, now.minute);
+                }
+            }
+            Event::Timer(5) => window::reap(),
+            Event::Timer(keyboard::KEYCMD_TIMER) => keyboard::poll_commands(),
+            Event::Timer(_) => {}
+            Event::Mouse(_) | Event::Input(_) | Event::User(_) | Event::Close(_) | Event::Wheel(_) | Event::Button(_) => {}
         }
-        io_sti();
-        io_hlt();
+    }
+}
+
+//后台任务：在counter窗口中不断显示当前的计数
+fn counter_task() -> ! {
+    let mut window: Vec<Color16> = vec![Color16::Black; 160 * 52];
+    io_cli();
+    *win_layer_index.lock() = LAYERCTL.lock().alloc().unwrap();
+    LAYERCTL.lock().set_buf(
+        *win_layer_index.lock(),
+        &mut window,
+        160,
+        52,
+        None
+    );
+    window::make_window(&mut window, 160, 52, "counter");
+    //没有接收事件的队列，点击关闭按钮后被强制结束
+    window::register(*win_layer_index.lock(), &window, None);
+    LAYERCTL.lock().slide(*win_layer_index.lock(), 80, 72);
+    LAYERCTL.lock().up_down(*win_layer_index.lock(), Some(1));
+    io_sti();
+
+    //LayerWriter在刷新时才关中断，其余时间可以被切换出去
+    let layer = *win_layer_index.lock();
+    loop {
+        let mut writer = LayerWriter::new(&mut window, layer, Color16::Black, 40, 28, 160, 52);
+        writer.set_background(Some(Color16::LightGrey));
+        window_print!(&mut writer, "{:>010}", timer::uptime());
     }
 }
 
@@ -183,6 +261,16 @@ pub fn exit_qemu(exit_code: QemuExitCode) {
     }
 }
 
+#[cfg(test)]
+fn test_runner(tests: &[&dyn Fn()]) {
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test();
+    }
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+}
+
 /// 这个函数将在 panic 时被调用
 #[panic_handler]
 fn panic(_info: &PanicInfo) -> ! {
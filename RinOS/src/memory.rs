@@ -1,7 +1,15 @@
 use x86_64::{structures::paging::PageTable, VirtAddr, PhysAddr};
 use x86_64::structures::paging::{FrameAllocator, OffsetPageTable, PhysFrame, Size4KiB};
+use core::sync::atomic::{AtomicU64, Ordering};
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 
+//可用的物理内存总量（字节）
+static USABLE_MEMORY: AtomicU64 = AtomicU64::new(0);
+
+pub fn usable_memory() -> u64 {
+    USABLE_MEMORY.load(Ordering::Relaxed)
+}
+
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
     next: usize,
@@ -9,6 +17,11 @@ pub struct BootInfoFrameAllocator {
 
 impl BootInfoFrameAllocator {
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
+        let usable = memory_map.iter()
+            .filter(|r| r.region_type == MemoryRegionType::Usable)
+            .map(|r| r.range.end_addr() - r.range.start_addr())
+            .sum();
+        USABLE_MEMORY.store(usable, Ordering::Relaxed);
         BootInfoFrameAllocator {
             memory_map,
             next: 0
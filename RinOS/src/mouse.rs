@@ -1,11 +1,10 @@
-use lazy_static::lazy_static;
-use ps2_mouse::{Mouse, MouseState};
 use x86_64::structures::idt::InterruptStackFrame;
-use crate::{asm, serial_println};
-use crate::int::{InterruptIndex, PICS};
+use crate::asm;
+use crate::int::{self, InterruptIndex};
 use spin::Mutex;
-use crate::layer::{bg_layer_index, mouse_layer_index};
-use crate::vga::update_mouse_cursor;
+use crate::compositor::MOUSE_FIFO;
+use crate::event::Event;
+use crate::i8042::{self, Device, Ps2Port};
 
 pub const MOUSE_CURSOR_WIDTH: usize = 16;
 pub const MOUSE_CURSOR_HEIGHT: usize = 16;
@@ -28,28 +27,190 @@ pub const MOUSE_CURSOR: [[u8; MOUSE_CURSOR_WIDTH]; MOUSE_CURSOR_HEIGHT] = [
     *b".............111"
 ];
 
-lazy_static! {
-    pub static ref MOUSE: Mutex<Mouse> = Mutex::new(Mouse::new());
+//MousePacket::buttons的位
+pub const MOUSE_LEFT: u8 = 0x01;
+pub const MOUSE_RIGHT: u8 = 0x02;
+pub const MOUSE_MIDDLE: u8 = 0x04;
+pub const MOUSE_BUTTON4: u8 = 0x08;
+pub const MOUSE_BUTTON5: u8 = 0x10;
+pub const MOUSE_BUTTONS: [u8; 5] = [MOUSE_LEFT, MOUSE_RIGHT, MOUSE_MIDDLE, MOUSE_BUTTON4, MOUSE_BUTTON5];
+
+//中断中解码完成的鼠标数据，交给任务处理
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MousePacket {
+    pub dx: i16,
+    pub dy: i16, //向上为正
+    pub dz: i8, //滚轮，向下为正
+    pub buttons: u8, //MOUSE_LEFT等的组合
 }
 
-pub extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    let packet = asm::io_in8(0x60);
-    MOUSE.lock().process_packet(packet);
+impl MousePacket {
+    pub fn moved(&self) -> bool {
+        self.dx != 0 || self.dy != 0
+    }
+
+    pub fn scrolled(&self) -> bool {
+        self.dz != 0
+    }
 
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
+    pub fn left(&self) -> bool {
+        self.buttons & MOUSE_LEFT != 0
     }
+
+    pub fn right(&self) -> bool {
+        self.buttons & MOUSE_RIGHT != 0
+    }
+
+    pub fn middle(&self) -> bool {
+        self.buttons & MOUSE_MIDDLE != 0
+    }
+}
+
+//3字节的数据包：按键和符号、X移动量、Y移动量
+//滚轮鼠标多一个字节：bit0～3为滚轮的移动量，5键鼠标的bit4、5为第4、5键
+pub struct MouseDec {
+    buf: [u8; 4],
+    phase: usize,
+    size: usize,
+    device: Device,
 }
 
-pub fn enable_mouse() {
-    MOUSE.lock().init().unwrap();
-    MOUSE.lock().set_on_complete(on_mouse_complete);
+impl MouseDec {
+    pub const fn new() -> MouseDec {
+        MouseDec { buf: [0; 4], phase: 0, size: 3, device: Device::Mouse }
+    }
+
+    pub fn set_device(&mut self, device: Device) {
+        self.device = device;
+        self.size = device.packet_size();
+        self.phase = 0;
+    }
+
+    pub fn decode(&mut self, data: u8) -> Option<MousePacket> {
+        //第一个字节的bit3总是1，据此对齐数据包
+        if self.phase == 0 && data & 0x08 == 0 {
+            return None;
+        }
+        self.buf[self.phase] = data;
+        self.phase += 1;
+        if self.phase < self.size {
+            return None;
+        }
+        self.phase = 0;
+        let flags = self.buf[0];
+        //溢出时移动量无意义
+        if flags & 0xc0 != 0 {
+            return None;
+        }
+        let mut dx = self.buf[1] as i16;
+        let mut dy = self.buf[2] as i16;
+        if flags & 0x10 != 0 {
+            dx -= 0x100;
+        }
+        if flags & 0x20 != 0 {
+            dy -= 0x100;
+        }
+        let mut buttons = flags & 0x07;
+        let mut dz = 0;
+        if self.size == 4 {
+            let extra = self.buf[3];
+            dz = extra as i8;
+            if self.device == Device::FiveButtonMouse {
+                //低4位为有符号的滚轮移动量
+                dz = ((extra << 4) as i8) >> 4;
+                if extra & 0x10 != 0 {
+                    buttons |= MOUSE_BUTTON4;
+                }
+                if extra & 0x20 != 0 {
+                    buttons |= MOUSE_BUTTON5;
+                }
+            }
+        }
+        Some(MousePacket { dx, dy, dz, buttons })
+    }
 }
 
-fn on_mouse_complete(mouse_state: MouseState) {
-    if mouse_state.moved() {
-        let dx = mouse_state.get_x();
-        let dy = mouse_state.get_y();
-        update_mouse_cursor(*bg_layer_index.lock(), *mouse_layer_index.lock(), dx as isize, -dy as isize);
+static MOUSE_DEC: Mutex<MouseDec> = Mutex::new(MouseDec::new());
+
+//IRQ12，数据来自第二个端口，不一定是鼠标
+pub extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    let data = asm::io_in8(0x60);
+    i8042::receive(Ps2Port::Second, data);
+
+    int::notify_end_of_interrupt(InterruptIndex::Mouse);
+}
+
+//由i8042在检测到鼠标后设定
+pub fn set_device(device: Device) {
+    MOUSE_DEC.lock().set_device(device);
+}
+
+//接有鼠标的端口收到的数据，设备的初始化由i8042完成
+pub fn receive(data: u8) {
+    if let Some(packet) = MOUSE_DEC.lock().decode(data) {
+        //只写入队列，光标由合成任务移动；队列满时丢弃，由overruns计数
+        let _ = MOUSE_FIFO.put(Event::Mouse(packet));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::i8042::Device;
+    use super::{MouseDec, MousePacket, MOUSE_BUTTON4, MOUSE_BUTTON5, MOUSE_LEFT, MOUSE_MIDDLE, MOUSE_RIGHT};
+
+    fn feed(dec: &mut MouseDec, bytes: &[u8]) -> Option<MousePacket> {
+        let mut packet = None;
+        for &data in bytes {
+            packet = dec.decode(data);
+        }
+        packet
+    }
+
+    #[test_case]
+    fn three_byte_packet() {
+        let mut dec = MouseDec::new();
+        assert_eq!(dec.decode(0x09), None);
+        assert_eq!(dec.decode(5), None);
+        assert_eq!(dec.decode(3), Some(MousePacket { dx: 5, dy: 3, dz: 0, buttons: MOUSE_LEFT }));
+    }
+
+    #[test_case]
+    fn negative_movement() {
+        let mut dec = MouseDec::new();
+        let packet = feed(&mut dec, &[0x3a, 0xfe, 0x80]);
+        assert_eq!(packet, Some(MousePacket { dx: -2, dy: -128, dz: 0, buttons: MOUSE_RIGHT }));
+    }
+
+    #[test_case]
+    fn overflow_is_dropped() {
+        let mut dec = MouseDec::new();
+        assert_eq!(feed(&mut dec, &[0x48, 0xff, 0x00]), None);
+        assert_eq!(feed(&mut dec, &[0x08, 1, 1]), Some(MousePacket { dx: 1, dy: 1, dz: 0, buttons: 0 }));
+    }
+
+    #[test_case]
+    fn wheel_byte() {
+        let mut dec = MouseDec::new();
+        dec.set_device(Device::WheelMouse);
+        assert_eq!(feed(&mut dec, &[0x08, 0, 0, 0x01]), Some(MousePacket { dx: 0, dy: 0, dz: 1, buttons: 0 }));
+        assert_eq!(feed(&mut dec, &[0x08, 0, 0, 0xff]), Some(MousePacket { dx: 0, dy: 0, dz: -1, buttons: 0 }));
+    }
+
+    #[test_case]
+    fn five_button_byte() {
+        let mut dec = MouseDec::new();
+        dec.set_device(Device::FiveButtonMouse);
+        assert_eq!(feed(&mut dec, &[0x08, 0, 0, 0x0f]), Some(MousePacket { dx: 0, dy: 0, dz: -1, buttons: 0 }));
+        let packet = feed(&mut dec, &[0x08, 0, 0, 0x31]);
+        assert_eq!(packet, Some(MousePacket { dx: 0, dy: 0, dz: 1, buttons: MOUSE_BUTTON4 | MOUSE_BUTTON5 }));
+    }
+
+    #[test_case]
+    fn realigns_on_bit3() {
+        let mut dec = MouseDec::new();
+        //第一个字节的bit3为0时丢弃，直到找到数据包的开头
+        assert_eq!(dec.decode(0x05), None);
+        assert_eq!(dec.decode(0x00), None);
+        assert_eq!(feed(&mut dec, &[0x0c, 2, 0xfd]), Some(MousePacket { dx: 2, dy: 253, dz: 0, buttons: MOUSE_MIDDLE }));
     }
 }
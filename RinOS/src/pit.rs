@@ -0,0 +1,53 @@
+use core::sync::atomic::{AtomicU32, Ordering};
+use core::time::Duration;
+use crate::asm::{io_in8, io_out8};
+use crate::timer::MAX_TIMEOUT;
+
+const PIT_CTRL: u16 = 0x0043;
+const PIT_CNT0: u16 = 0x0040;
+const PIT_CNT2: u16 = 0x0042;
+//bit0为通道2的GATE，bit1为扬声器，bit5为通道2的OUT
+const PORT_SPEAKER: u16 = 0x0061;
+//PIT的输入时钟频率（Hz）
+pub const PIT_BASE_FREQUENCY: u32 = 1193182;
+pub const DEFAULT_FREQUENCY: u32 = 100;
+
+//当前的tick频率，未设定时为BIOS的默认值（约18.2Hz）
+static FREQUENCY: AtomicU32 = AtomicU32::new(PIT_BASE_FREQUENCY / 65536);
+
+//将通道0设为以hz频率产生中断（模式2，先写低8位再写高8位）
+pub fn init(hz: u32) {
+    let hz = hz.max(1);
+    let divisor = (PIT_BASE_FREQUENCY / hz).clamp(1, 65535);
+    io_out8(PIT_CTRL, 0x34);
+    io_out8(PIT_CNT0, (divisor & 0xff) as u8);
+    io_out8(PIT_CNT0, (divisor >> 8) as u8);
+    FREQUENCY.store(PIT_BASE_FREQUENCY / divisor, Ordering::Relaxed);
+}
+
+//用通道2忙等待count个PIT时钟（模式0，到时后OUT变为高电平），不使用中断
+//count为65535时约55ms
+pub fn busy_wait(count: u16) {
+    let speaker = io_in8(PORT_SPEAKER);
+    io_out8(PORT_SPEAKER, (speaker & !0x02) | 0x01);
+    io_out8(PIT_CTRL, 0xb0);
+    io_out8(PIT_CNT2, (count & 0xff) as u8);
+    io_out8(PIT_CNT2, (count >> 8) as u8);
+    while io_in8(PORT_SPEAKER) & 0x20 == 0 {}
+    io_out8(PORT_SPEAKER, speaker);
+}
+
+pub fn frequency() -> u32 {
+    FREQUENCY.load(Ordering::Relaxed)
+}
+
+//向上取整，保证定时器不会比指定的时间更早到时；超过MAX_TIMEOUT时取MAX_TIMEOUT
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    let ticks = (ms as u128 * frequency() as u128 + 999) / 1000;
+    ticks.min(MAX_TIMEOUT as u128) as u64
+}
+
+pub fn duration_to_ticks(duration: Duration) -> u64 {
+    let ticks = (duration.as_nanos() * frequency() as u128 + 999_999_999) / 1_000_000_000;
+    ticks.min(MAX_TIMEOUT as u128) as u64
+}
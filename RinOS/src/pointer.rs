@@ -0,0 +1,140 @@
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+//光标图像中指向的位置（箭头的尖端）
+pub const HOTSPOT_X: isize = 0;
+pub const HOTSPOT_Y: isize = 0;
+
+//移动量以1/100像素计算，低灵敏度时不会丢失小的移动
+const SCALE: i32 = 100;
+//加速后的倍率不超过sensitivity的4倍
+const MAX_GAIN: i32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PointerConfig {
+    pub sensitivity: u32, //百分比，100为鼠标的移动量不变
+    pub accel: u32, //速度每超过threshold 1，倍率增加的百分比
+    pub threshold: u32, //一帧内的移动量（像素）超过该值时加速
+}
+
+impl PointerConfig {
+    pub const fn new() -> PointerConfig {
+        PointerConfig { sensitivity: 100, accel: 10, threshold: 4 }
+    }
+
+    //速度为speed时的倍率（百分比），用i64计算，限制后不会溢出i32
+    pub fn gain(&self, speed: i32) -> i32 {
+        let base = self.sensitivity as i64;
+        let over = (speed as i64 - self.threshold as i64).max(0);
+        let gain = base + base * over * self.accel as i64 / 100;
+        gain.min(base * MAX_GAIN as i64) as i32
+    }
+}
+
+static CONFIG: Mutex<PointerConfig> = Mutex::new(PointerConfig::new());
+
+pub fn config() -> PointerConfig {
+    interrupts::without_interrupts(|| *CONFIG.lock())
+}
+
+pub fn set_config(config: PointerConfig) {
+    interrupts::without_interrupts(|| *CONFIG.lock() = config);
+}
+
+//光标的位置，记录的是热点而不是图层的左上角
+pub struct Pointer {
+    x: isize,
+    y: isize,
+    rem_x: i32, //不足1像素的移动量
+    rem_y: i32,
+}
+
+impl Pointer {
+    pub fn new(x: isize, y: isize) -> Pointer {
+        let mut pointer = Pointer { x: 0, y: 0, rem_x: 0, rem_y: 0 };
+        pointer.move_to(x, y);
+        pointer
+    }
+
+    //热点限制在屏幕内，图层可以超出屏幕的右端和下端
+    pub fn move_to(&mut self, x: isize, y: isize) {
+        self.x = x.clamp(0, SCREEN_WIDTH as isize - 1);
+        self.y = y.clamp(0, SCREEN_HEIGHT as isize - 1);
+    }
+
+    //按灵敏度和加速曲线移动，dy向下为正；位置变化时返回true
+    pub fn apply(&mut self, dx: i32, dy: i32, config: &PointerConfig) -> bool {
+        let gain = config.gain(dx.abs().max(dy.abs()));
+        let mx = dx * gain + self.rem_x;
+        let my = dy * gain + self.rem_y;
+        self.rem_x = mx % SCALE;
+        self.rem_y = my % SCALE;
+        let (old_x, old_y) = (self.x, self.y);
+        self.move_to(self.x + (mx / SCALE) as isize, self.y + (my / SCALE) as isize);
+        //碰到屏幕边缘时丢弃余数
+        if self.x != old_x + (mx / SCALE) as isize {
+            self.rem_x = 0;
+        }
+        if self.y != old_y + (my / SCALE) as isize {
+            self.rem_y = 0;
+        }
+        (self.x, self.y) != (old_x, old_y)
+    }
+
+    pub fn position(&self) -> (usize, usize) {
+        (self.x as usize, self.y as usize)
+    }
+
+    //光标图层的左上角，图层的坐标不能为负
+    pub fn sprite_origin(&self) -> (usize, usize) {
+        ((self.x - HOTSPOT_X).max(0) as usize, (self.y - HOTSPOT_Y).max(0) as usize)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{SCREEN_HEIGHT, SCREEN_WIDTH};
+    use super::{Pointer, PointerConfig};
+
+    #[test_case]
+    fn gain_accelerates_above_threshold() {
+        let config = PointerConfig::new();
+        assert_eq!(config.gain(0), 100);
+        assert_eq!(config.gain(4), 100);
+        assert_eq!(config.gain(5), 110);
+        assert_eq!(config.gain(10), 160);
+        //不超过sensitivity的4倍
+        assert_eq!(config.gain(1000), 400);
+    }
+
+    #[test_case]
+    fn gain_does_not_overflow() {
+        let config = PointerConfig { sensitivity: 1000, accel: 100, threshold: 0 };
+        assert_eq!(config.gain(i32::MAX), 4000);
+        let config = PointerConfig { sensitivity: 100, accel: 10, threshold: u32::MAX };
+        assert_eq!(config.gain(i32::MAX), 100);
+    }
+
+    #[test_case]
+    fn apply_keeps_subpixel_remainder() {
+        let config = PointerConfig { sensitivity: 50, accel: 0, threshold: 4 };
+        let mut pointer = Pointer::new(100, 100);
+        assert!(!pointer.apply(1, -1, &config));
+        assert_eq!(pointer.position(), (100, 100));
+        assert!(pointer.apply(1, -1, &config));
+        assert_eq!(pointer.position(), (101, 99));
+    }
+
+    #[test_case]
+    fn apply_clamps_to_screen() {
+        let config = PointerConfig::new();
+        let mut pointer = Pointer::new(5, 5);
+        assert!(pointer.apply(-10, 0, &config));
+        assert_eq!(pointer.position(), (0, 5));
+        let mut pointer = Pointer::new(SCREEN_WIDTH as isize - 2, SCREEN_HEIGHT as isize - 2);
+        assert!(pointer.apply(100, 100, &config));
+        assert_eq!(pointer.position(), (SCREEN_WIDTH - 1, SCREEN_HEIGHT - 1));
+        assert!(!pointer.apply(1, 1, &config));
+    }
+}
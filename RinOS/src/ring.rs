@@ -0,0 +1,65 @@
+use core::cell::UnsafeCell;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+//单生产者单消费者的无锁环形缓冲区
+//生产者与消费者之间不需要加锁，但同时只能有一个生产者和一个消费者
+//有多个生产者（或消费者）时由调用者互斥，例如Fifo在关中断时调用
+pub(crate) struct RingBuffer<T: Copy, const N: usize> {
+    buf: UnsafeCell<[Option<T>; N]>,
+    head: AtomicUsize, //下一个写入的位置，只由生产者修改
+    tail: AtomicUsize, //下一个读出的位置，只由消费者修改
+    overruns: AtomicUsize, //缓冲区满时被丢弃的数据数
+}
+
+//同一个槽只会被生产者或消费者中的一方访问，由head和tail保证
+unsafe impl<T: Copy + Send, const N: usize> Sync for RingBuffer<T, N> {}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> RingBuffer<T, N> {
+        RingBuffer {
+            buf: UnsafeCell::new([None; N]),
+            head: AtomicUsize::new(0),
+            tail: AtomicUsize::new(0),
+            overruns: AtomicUsize::new(0),
+        }
+    }
+
+    //缓冲区满时丢弃数据并计数
+    //调用者必须保证没有其他的push同时执行
+    pub unsafe fn push(&self, data: T) -> Result<(), T> {
+        let head = self.head.load(Ordering::Relaxed);
+        let tail = self.tail.load(Ordering::Acquire);
+        if head.wrapping_sub(tail) == N {
+            self.overruns.fetch_add(1, Ordering::Relaxed);
+            return Err(data);
+        }
+        (*self.buf.get())[head % N] = Some(data);
+        self.head.store(head.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    //调用者必须保证没有其他的pop同时执行
+    pub unsafe fn pop(&self) -> Option<T> {
+        let tail = self.tail.load(Ordering::Relaxed);
+        let head = self.head.load(Ordering::Acquire);
+        if head == tail {
+            return None;
+        }
+        let data = (*self.buf.get())[tail % N];
+        self.tail.store(tail.wrapping_add(1), Ordering::Release);
+        data
+    }
+
+    pub fn len(&self) -> usize {
+        let tail = self.tail.load(Ordering::Acquire);
+        self.head.load(Ordering::Acquire).wrapping_sub(tail)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn overruns(&self) -> usize {
+        self.overruns.load(Ordering::Relaxed)
+    }
+}
@@ -0,0 +1,139 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::interrupts;
+use x86_64::structures::idt::InterruptStackFrame;
+use crate::asm::{io_in8, io_out8};
+use crate::int::{self, InterruptIndex};
+
+const CMOS_ADDR: u16 = 0x0070;
+const CMOS_DATA: u16 = 0x0071;
+
+//CMOS中的寄存器
+const RTC_SECOND: u8 = 0x00;
+const RTC_MINUTE: u8 = 0x02;
+const RTC_HOUR: u8 = 0x04;
+const RTC_DAY: u8 = 0x07;
+const RTC_MONTH: u8 = 0x08;
+const RTC_YEAR: u8 = 0x09;
+const RTC_CENTURY: u8 = 0x32; //不是所有机器都有
+const RTC_STATUS_A: u8 = 0x0a;
+const RTC_STATUS_B: u8 = 0x0b;
+const RTC_STATUS_C: u8 = 0x0c;
+
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 0x80;
+const STATUS_B_24_HOUR: u8 = 0x02;
+const STATUS_B_BINARY: u8 = 0x04;
+const STATUS_B_PERIODIC: u8 = 0x40;
+const HOUR_PM: u8 = 0x80;
+
+//周期中断的次数
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8, //24小时制
+    pub minute: u8,
+    pub second: u8,
+}
+
+fn cmos_read(reg: u8) -> u8 {
+    io_out8(CMOS_ADDR, reg);
+    io_in8(CMOS_DATA)
+}
+
+fn cmos_write(reg: u8, data: u8) {
+    io_out8(CMOS_ADDR, reg);
+    io_out8(CMOS_DATA, data);
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value & 0x0f) + (value >> 4) * 10
+}
+
+//未转换的寄存器值，依次为秒、分、时、日、月、年、世纪
+fn read_raw() -> [u8; 7] {
+    while cmos_read(RTC_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0 {}
+    [
+        cmos_read(RTC_SECOND),
+        cmos_read(RTC_MINUTE),
+        cmos_read(RTC_HOUR),
+        cmos_read(RTC_DAY),
+        cmos_read(RTC_MONTH),
+        cmos_read(RTC_YEAR),
+        cmos_read(RTC_CENTURY),
+    ]
+}
+
+//读出当前的日期和时间
+//更新中读出的值可能不一致，因此读到两次相同的值为止
+pub fn read() -> DateTime {
+    let raw = interrupts::without_interrupts(|| {
+        let mut last = read_raw();
+        loop {
+            let raw = read_raw();
+            if raw == last {
+                break raw;
+            }
+            last = raw;
+        }
+    });
+    let status_b = interrupts::without_interrupts(|| cmos_read(RTC_STATUS_B));
+    let [mut second, mut minute, mut hour, mut day, mut month, mut year, mut century] = raw;
+
+    let pm = hour & HOUR_PM != 0;
+    hour &= !HOUR_PM;
+    if status_b & STATUS_B_BINARY == 0 {
+        second = bcd_to_binary(second);
+        minute = bcd_to_binary(minute);
+        hour = bcd_to_binary(hour);
+        day = bcd_to_binary(day);
+        month = bcd_to_binary(month);
+        year = bcd_to_binary(year);
+        century = bcd_to_binary(century);
+    }
+    //12小时制的12点是0点或12点
+    if status_b & STATUS_B_24_HOUR == 0 {
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+    //没有世纪寄存器时按21世纪处理
+    let year = if (19..=21).contains(&century) {
+        century as u16 * 100 + year as u16
+    } else {
+        2000 + year as u16
+    };
+    DateTime { year, month, day, hour, minute, second }
+}
+
+//开启IRQ8的周期中断，频率为32768 >> (rate - 1) Hz，rate为3～15
+pub fn enable_periodic_interrupt(rate: u8) {
+    let rate = rate.clamp(3, 15);
+    interrupts::without_interrupts(|| {
+        let status_a = cmos_read(RTC_STATUS_A);
+        cmos_write(RTC_STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = cmos_read(RTC_STATUS_B);
+        cmos_write(RTC_STATUS_B, status_b | STATUS_B_PERIODIC);
+        //读出C寄存器，否则不会产生下一次中断
+        cmos_read(RTC_STATUS_C);
+        int::enable_irq(InterruptIndex::Rtc);
+    });
+}
+
+pub fn periodic_frequency(rate: u8) -> u32 {
+    32768 >> (rate.clamp(3, 15) - 1)
+}
+
+//启用周期中断以来的中断次数
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+pub extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+    cmos_read(RTC_STATUS_C);
+    int::notify_end_of_interrupt(InterruptIndex::Rtc);
+}
@@ -0,0 +1,416 @@
+use alloc::alloc::{alloc, Layout};
+use core::arch::global_asm;
+use core::cmp::{max, min};
+use core::ptr::{addr_of, addr_of_mut};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use crate::asm::{io_hlt, io_sti};
+use crate::{fifo, timer, window};
+use crate::timer::TIMER_CTL;
+
+const MAX_TASKS: usize = 256;
+const MAX_TASKS_LV: usize = 100; //每个level中最多的任务数
+const MAX_TASKLEVELS: usize = 10;
+const TASK_STACK_SIZE: usize = 64 * 1024;
+//未指定时的优先级，即一次运行的时间片长度（timer tick）
+pub const DEFAULT_PRIORITY: u32 = 2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TaskState {
+    Available,
+    Sleeping,
+    Running,
+}
+
+//任务切换时保存的寄存器，字段顺序与下面的汇编一致
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+pub struct Context {
+    rsp: u64,
+    rbx: u64,
+    rbp: u64,
+    r12: u64,
+    r13: u64,
+    r14: u64,
+    r15: u64,
+    rflags: u64,
+}
+
+impl Context {
+    pub fn new() -> Context {
+        Context {
+            rsp: 0,
+            rbx: 0,
+            rbp: 0,
+            r12: 0,
+            r13: 0,
+            r14: 0,
+            r15: 0,
+            rflags: 0,
+        }
+    }
+}
+
+//rdi: 当前任务的Context，rsi: 切换目标的Context
+//返回地址留在各自的栈上，ret之后回到目标任务上次调用switch_context的地方
+global_asm!(
+    ".global rinos_switch_context",
+    "rinos_switch_context:",
+    "mov [rdi + 0x00], rsp",
+    "mov [rdi + 0x08], rbx",
+    "mov [rdi + 0x10], rbp",
+    "mov [rdi + 0x18], r12",
+    "mov [rdi + 0x20], r13",
+    "mov [rdi + 0x28], r14",
+    "mov [rdi + 0x30], r15",
+    "pushfq",
+    "pop qword ptr [rdi + 0x38]",
+    "mov rsp, [rsi + 0x00]",
+    "mov rbx, [rsi + 0x08]",
+    "mov rbp, [rsi + 0x10]",
+    "mov r12, [rsi + 0x18]",
+    "mov r13, [rsi + 0x20]",
+    "mov r14, [rsi + 0x28]",
+    "mov r15, [rsi + 0x30]",
+    "push qword ptr [rsi + 0x38]",
+    "popfq",
+    "ret",
+    //新任务第一次被切换到时从这里开始，r12中是入口函数
+    ".global rinos_task_trampoline",
+    "rinos_task_trampoline:",
+    "mov rdi, r12",
+    "call {start}",
+    "ud2",
+    start = sym task_start,
+);
+
+extern "C" {
+    fn rinos_switch_context(old: *mut Context, new: *const Context);
+    fn rinos_task_trampoline();
+}
+
+extern "C" fn task_start(entry: usize) -> ! {
+    let entry: fn() -> ! = unsafe { core::mem::transmute(entry) };
+    io_sti();
+    entry()
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Task {
+    pub state: TaskState,
+    pub level: usize, //数字越小越优先，只有高level中没有运行的任务时才会切换到低level
+    pub priority: u32, //时间片长度（timer tick）
+    pub context: Context,
+    pub stack: usize, //内核栈的起始地址，0表示使用引导时的栈
+}
+
+impl Task {
+    pub fn new() -> Task {
+        Task {
+            state: TaskState::Available,
+            level: 0,
+            priority: DEFAULT_PRIORITY,
+            context: Context::new(),
+            stack: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct TaskLevel {
+    pub running: usize, //正在运行的任务数
+    pub now: usize, //当前任务在tasks中的位置
+    pub tasks: [usize; MAX_TASKS_LV],
+}
+
+impl TaskLevel {
+    pub fn new() -> TaskLevel {
+        TaskLevel {
+            running: 0,
+            now: 0,
+            tasks: [0; MAX_TASKS_LV],
+        }
+    }
+}
+
+pub struct TaskCtl {
+    pub current: usize, //正在运行的任务，切换时保存寄存器的位置
+    pub now_lv: usize, //当前运行的level
+    pub lv_change: bool, //下次切换时是否需要重新选择level
+    pub level: [TaskLevel; MAX_TASKLEVELS],
+    pub tasks_data: [Task; MAX_TASKS],
+}
+
+impl TaskCtl {
+    pub fn new() -> TaskCtl {
+        TaskCtl {
+            current: 0,
+            now_lv: 0,
+            lv_change: false,
+            level: [TaskLevel::new(); MAX_TASKLEVELS],
+            tasks_data: [Task::new(); MAX_TASKS],
+        }
+    }
+
+    pub fn alloc(&mut self) -> Option<usize> {
+        for i in 0..MAX_TASKS {
+            if self.tasks_data[i].state == TaskState::Available {
+                self.tasks_data[i].state = TaskState::Sleeping;
+                self.tasks_data[i].context = Context::new();
+                return Some(i);
+            }
+        }
+        None
+    }
+
+    //为任务分配内核栈，并让它第一次被切换到时进入entry
+    pub fn init_task(&mut self, task_id: usize, entry: fn() -> !) {
+        if self.tasks_data[task_id].stack == 0 {
+            let layout = Layout::from_size_align(TASK_STACK_SIZE, 16).unwrap();
+            self.tasks_data[task_id].stack = unsafe { alloc(layout) } as usize;
+            assert!(self.tasks_data[task_id].stack != 0, "task stack allocation failed");
+        }
+        let stack_top = (self.tasks_data[task_id].stack + TASK_STACK_SIZE) as u64;
+        //栈顶放入trampoline的地址作为switch_context的返回地址
+        let rsp = stack_top - 8;
+        unsafe { *(rsp as *mut u64) = rinos_task_trampoline as usize as u64; }
+        let context = &mut self.tasks_data[task_id].context;
+        *context = Context::new();
+        context.rsp = rsp;
+        context.r12 = entry as usize as u64;
+        context.rflags = 0x2; //中断在task_start中打开
+    }
+
+    //正在运行的任务改变level后，now处的不一定是它
+    pub fn current(&self) -> usize {
+        self.current
+    }
+
+    //轮转中now_lv的now处的任务，即下一个要运行的任务
+    fn scheduled(&self) -> usize {
+        let tl = &self.level[self.now_lv];
+        tl.tasks[tl.now]
+    }
+
+    //level已满时返回false
+    fn add(&mut self, task_id: usize) -> bool {
+        let tl = &mut self.level[self.tasks_data[task_id].level];
+        if tl.running >= MAX_TASKS_LV {
+            return false;
+        }
+        tl.tasks[tl.running] = task_id;
+        tl.running += 1;
+        self.tasks_data[task_id].state = TaskState::Running;
+        true
+    }
+
+    fn remove(&mut self, task_id: usize) {
+        let tl = &mut self.level[self.tasks_data[task_id].level];
+        let mut idx = tl.running;
+        for i in 0..tl.running {
+            if tl.tasks[i] == task_id {
+                idx = i;
+                break;
+            }
+        }
+        if idx == tl.running {
+            return;
+        }
+        tl.running -= 1;
+        if idx < tl.now {
+            tl.now -= 1;
+        }
+        if tl.now >= tl.running {
+            tl.now = 0;
+        }
+        for i in idx..tl.running {
+            tl.tasks[i] = tl.tasks[i + 1];
+        }
+        self.tasks_data[task_id].state = TaskState::Sleeping;
+    }
+
+    //切换到有任务在运行的最高level
+    fn switch_sub(&mut self) {
+        for i in 0..MAX_TASKLEVELS {
+            if self.level[i].running > 0 {
+                self.now_lv = i;
+                break;
+            }
+        }
+        self.lv_change = false;
+    }
+
+    //level和priority为None时保持不变，对正在运行的任务也可以调用
+    //目标level已满时任务保持原来的状态
+    pub fn run(&mut self, task_id: usize, level: Option<usize>, priority: Option<u32>) -> Result<(), &'static str> {
+        //已结束的任务，例如仍留在队列中的所属任务
+        if self.tasks_data[task_id].state == TaskState::Available {
+            return Ok(());
+        }
+        let level = min(level.unwrap_or(self.tasks_data[task_id].level), MAX_TASKLEVELS - 1);
+        let running = self.tasks_data[task_id].state == TaskState::Running;
+        if (!running || self.tasks_data[task_id].level != level) && self.level[level].running >= MAX_TASKS_LV {
+            return Err("Task level is full");
+        }
+        if let Some(priority) = priority {
+            self.tasks_data[task_id].priority = max(priority, 1);
+        }
+        if running && self.tasks_data[task_id].level != level {
+            self.remove(task_id);
+        }
+        if self.tasks_data[task_id].state != TaskState::Running {
+            self.tasks_data[task_id].level = level;
+            self.add(task_id);
+        }
+        self.lv_change = true;
+        Ok(())
+    }
+}
+
+lazy_static! {
+    pub static ref TASK_CTL: Mutex<TaskCtl> = Mutex::new(TaskCtl::new());
+}
+
+unsafe fn switch_to(mut ctl: spin::MutexGuard<TaskCtl>, old: usize, new: usize) {
+    if old == new {
+        return;
+    }
+    ctl.current = new;
+    let old_ctx = addr_of_mut!(ctl.tasks_data[old].context);
+    let new_ctx = addr_of!(ctl.tasks_data[new].context);
+    //切换前必须释放锁，否则下一个任务将无法再次获取
+    drop(ctl);
+    rinos_switch_context(old_ctx, new_ctx);
+}
+
+//所有任务都休眠时运行的任务
+fn idle_task() -> ! {
+    loop {
+        io_hlt();
+    }
+}
+
+//把当前的执行流程（kernel_main）登记为第一个任务，并启动任务切换定时器
+pub fn init() -> usize {
+    interrupts::without_interrupts(|| {
+        let mut ctl = TASK_CTL.lock();
+        let task_id = ctl.alloc().unwrap();
+        ctl.run(task_id, Some(0), Some(DEFAULT_PRIORITY)).unwrap();
+        ctl.switch_sub();
+        ctl.current = task_id;
+        let idle = ctl.alloc().unwrap();
+        ctl.init_task(idle, idle_task);
+        ctl.run(idle, Some(MAX_TASKLEVELS - 1), Some(1)).unwrap();
+        let mut timer_ctl = TIMER_CTL.lock();
+        let timer = timer_ctl.alloc().unwrap();
+        timer_ctl.task_timer = Some(timer);
+        timer_ctl.set_time(timer, DEFAULT_PRIORITY as u64);
+        task_id
+    })
+}
+
+//创建新任务并以指定的level和priority加入运行队列，没有空位或level已满时返回None
+pub fn spawn(entry: fn() -> !, level: usize, priority: u32) -> Option<usize> {
+    interrupts::without_interrupts(|| {
+        let mut ctl = TASK_CTL.lock();
+        let task_id = ctl.alloc()?;
+        ctl.init_task(task_id, entry);
+        if ctl.run(task_id, Some(level), Some(priority)).is_err() {
+            ctl.tasks_data[task_id].state = TaskState::Available;
+            return None;
+        }
+        Some(task_id)
+    })
+}
+
+//运行任务，或修改正在运行的任务的level与priority
+pub fn run(task_id: usize, level: Option<usize>, priority: Option<u32>) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| TASK_CTL.lock().run(task_id, level, priority))
+}
+
+pub fn current() -> usize {
+    interrupts::without_interrupts(|| TASK_CTL.lock().current())
+}
+
+//让任务休眠，直到被run唤醒；休眠的是当前任务时立即切换到其他任务
+pub fn sleep(task_id: usize) {
+    interrupts::without_interrupts(|| {
+        let mut ctl = TASK_CTL.lock();
+        if ctl.tasks_data[task_id].state != TaskState::Running {
+            return;
+        }
+        let old = ctl.current();
+        ctl.remove(task_id);
+        if task_id == old {
+            ctl.switch_sub();
+            let new = ctl.scheduled();
+            unsafe { switch_to(ctl, old, new); }
+        }
+    });
+}
+
+//强制结束其他任务，不能结束当前任务
+//关中断时才获取的锁（包括堆）不会被持有；栈保留给之后创建的任务使用
+pub fn kill(task_id: usize) -> Result<(), &'static str> {
+    interrupts::without_interrupts(|| {
+        let mut ctl = TASK_CTL.lock();
+        if task_id == ctl.current() {
+            return Err("Cannot kill the current task");
+        }
+        if ctl.tasks_data[task_id].state == TaskState::Available {
+            return Err("No such task");
+        }
+        ctl.remove(task_id);
+        //在任务号可以被重新使用之前释放
+        release(task_id);
+        ctl.tasks_data[task_id].state = TaskState::Available;
+        ctl.lv_change = true;
+        Ok(())
+    })
+}
+
+//释放已结束的任务的定时器和窗口，解除队列与它的关联
+fn release(task_id: usize) {
+    timer::free_task(task_id);
+    fifo::unbind_task(task_id);
+    window::release_task(task_id);
+}
+
+//结束当前任务，不再返回
+pub fn exit() -> ! {
+    interrupts::disable();
+    release(current());
+    let mut ctl = TASK_CTL.lock();
+    let old = ctl.current();
+    ctl.remove(old);
+    ctl.tasks_data[old].state = TaskState::Available;
+    ctl.switch_sub();
+    let new = ctl.scheduled();
+    unsafe { switch_to(ctl, old, new); }
+    unreachable!("exited task was resumed")
+}
+
+//由定时器中断调用，轮转到下一个任务
+pub fn switch() {
+    interrupts::without_interrupts(|| {
+        let mut ctl = TASK_CTL.lock();
+        let old = ctl.current();
+        let now_lv = ctl.now_lv;
+        let tl = &mut ctl.level[now_lv];
+        tl.now += 1;
+        if tl.now >= tl.running {
+            tl.now = 0;
+        }
+        if ctl.lv_change {
+            ctl.switch_sub();
+        }
+        let new = ctl.scheduled();
+        let mut timer_ctl = TIMER_CTL.lock();
+        if let Some(timer) = timer_ctl.task_timer {
+            timer_ctl.set_time(timer, ctl.tasks_data[new].priority as u64);
+        }
+        drop(timer_ctl);
+        unsafe { switch_to(ctl, old, new); }
+    });
+}
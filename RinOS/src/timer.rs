@@ -1,13 +1,37 @@
+use alloc::boxed::Box;
+use alloc::vec::Vec;
+use core::time::Duration;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::instructions::interrupts;
 use x86_64::structures::idt::InterruptStackFrame;
-use crate::asm::{io_cli, io_load_flags, io_store_flags};
-use crate::fifo::Fifo;
-use crate::int::{InterruptIndex, PICS};
+use crate::event::{Event, EventSink};
+use crate::int::{self, InterruptIndex};
+use crate::pit;
 use crate::serial_print;
+use crate::task;
 
-const MAX_TIMER_COUNT: usize = 500;
+//时间轮：每层64个槽，第L层的一个槽对应64^L个tick
+//11层可以覆盖64位的计数
+const WHEEL_BITS: u32 = 6;
+const WHEEL_SIZE: usize = 1 << WHEEL_BITS;
+const WHEEL_MASK: u64 = WHEEL_SIZE as u64 - 1;
+const WHEEL_LEVELS: usize = 11;
+//最后一个链表存放本tick到时、尚未处理的定时器
+const EXPIRED_LIST: usize = WHEEL_LEVELS * WHEEL_SIZE;
+const NIL: usize = usize::MAX;
+
+//定时器的最长时间（tick），再长的时间在time_before看来会是过去的时刻
+pub const MAX_TIMEOUT: u64 = i64::MAX as u64;
+
+//a是否早于b
+//按差值的符号比较，计数回绕之后先后顺序仍然正确（两者相差不超过2^63）
+pub fn time_before(a: u64, b: u64) -> bool {
+    (a.wrapping_sub(b) as i64) < 0
+}
+
+//在中断中调用的回调，不能在其中操作定时器
+pub type TimerCallback = Box<dyn FnMut() + Send>;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum TimerState {
@@ -18,127 +42,536 @@ pub enum TimerState {
 
 #[derive(Debug, Clone, Copy)]
 pub struct Timer {
-    pub timeout: u32,
+    pub timeout: u64,
+    pub interval: Option<u64>, //周期定时器到时后自动以该间隔重新设定
     pub flag: TimerState,
-    pub fifo_addr: usize,
-    pub data: u8
+    pub fifo: Option<*const dyn EventSink>,
+    pub data: u32,
+    pub owner: Option<usize>, //创建TimerHandle的任务，任务结束时释放
+    //所在链表的双向链接，未使用的定时器通过next连成空闲链表
+    prev: usize,
+    next: usize,
+    list: usize,
 }
 
 impl Timer {
     pub fn new() -> Timer {
         Timer {
             timeout: 0,
+            interval: None,
             flag: TimerState::Available,
-            fifo_addr: 0,
-            data: 0
+            fifo: None,
+            data: 0,
+            owner: None,
+            prev: NIL,
+            next: NIL,
+            list: NIL,
         }
     }
 }
 
 pub struct TimerCtl {
-    pub count: u32,
-    pub next: u32,
-    pub counting: u32,
-    pub timers: [usize; MAX_TIMER_COUNT],
-    pub timers_data: [Timer; MAX_TIMER_COUNT],
+    pub count: u64, //启动以来的tick数
+    pub counting: u32, //正在运行的定时器数
+    pub timers_data: Vec<Timer>, //数量不足时扩充，不在中断中分配
+    pub callbacks: Vec<Option<TimerCallback>>,
+    pub task_timer: Option<usize>, //用于任务切换的定时器
+    wheel: [usize; EXPIRED_LIST + 1], //各个槽中链表的头
+    free: usize, //空闲链表的头
 }
 
 impl TimerCtl {
-    pub fn new() -> TimerCtl {
+    //不分配内存，在堆初始化之前也可以使用
+    pub const fn new() -> TimerCtl {
         TimerCtl {
             count: 0,
-            next: 0,
             counting: 0,
-            timers: [0; MAX_TIMER_COUNT],
-            timers_data: [Timer::new(); MAX_TIMER_COUNT],
+            timers_data: Vec::new(),
+            callbacks: Vec::new(),
+            task_timer: None,
+            wheel: [NIL; EXPIRED_LIST + 1],
+            free: NIL,
         }
     }
 
     pub fn alloc(&mut self) -> Result<usize, &'static str> {
-        for i in 0..MAX_TIMER_COUNT {
-            if self.timers_data[i].flag == TimerState::Available {
-                self.timers_data[i].flag = TimerState::InUse;
-                return Ok(i);
+        let timer_id = if self.free != NIL {
+            let timer_id = self.free;
+            self.free = self.timers_data[timer_id].next;
+            timer_id
+        } else {
+            if self.timers_data.try_reserve(1).is_err() || self.callbacks.try_reserve(1).is_err() {
+                return Err("No available timer");
             }
-        }
-        Err("No available timer")
+            self.timers_data.push(Timer::new());
+            self.callbacks.push(None);
+            self.timers_data.len() - 1
+        };
+        self.timers_data[timer_id] = Timer::new();
+        self.timers_data[timer_id].flag = TimerState::InUse;
+        Ok(timer_id)
+    }
+
+    //正在运行的定时器会先被取消，再按新的时间设定
+    pub fn set_time(&mut self, timer_id: usize, timeout: u64) {
+        self.cancel(timer_id);
+        let timeout = self.count.wrapping_add(timeout.min(MAX_TIMEOUT));
+        self.insert(timer_id, timeout);
     }
 
-    pub fn set_time(&mut self, timer_id: usize, timeout: u32) {
-        let mut timer = self.timers_data[timer_id];
-        timer.timeout = timeout + self.count;
+    pub fn set_time_ms(&mut self, timer_id: usize, ms: u64) {
+        self.set_time(timer_id, pit::ms_to_ticks(ms));
+    }
+
+    pub fn set_time_duration(&mut self, timer_id: usize, duration: Duration) {
+        self.set_time(timer_id, pit::duration_to_ticks(duration));
+    }
+
+    //按到时的时刻放入时间轮中对应的槽
+    fn insert(&mut self, timer_id: usize, timeout: u64) {
+        let timer = &mut self.timers_data[timer_id];
+        timer.timeout = timeout;
         timer.flag = TimerState::Running;
-        let eflags = io_load_flags();
-        io_cli();
-        let mut insert_idx: usize = 0;
-        for i in 0..self.counting {
-            insert_idx = i as usize;
-            let t = self.timers_data[self.timers[i as usize]];
-            if t.timeout >= timer.timeout {
-                break;
-            }
+        self.counting += 1;
+        self.wheel_add(timer_id);
+    }
+
+    //下一个要处理的tick是count+1，已经过时的定时器在该tick到时
+    fn wheel_add(&mut self, timer_id: usize) {
+        let base = self.count.wrapping_add(1);
+        let mut expires = self.timers_data[timer_id].timeout;
+        if time_before(expires, base) {
+            expires = base;
         }
-        let mut j = self.counting as usize;
-        while j > insert_idx {
-            self.timers[j] = self.timers[j - 1];
-            j -= 1;
+        let delta = expires.wrapping_sub(base);
+        let mut level = 0;
+        while level < WHEEL_LEVELS - 1 && delta >> (WHEEL_BITS * (level as u32 + 1)) != 0 {
+            level += 1;
         }
-        self.counting += 1;
-        self.timers[insert_idx] = timer_id;
-        self.next = self.timers_data[self.timers[0]].timeout;
-        io_store_flags(eflags);
+        let slot = (expires >> (WHEEL_BITS * level as u32)) & WHEEL_MASK;
+        self.list_push(level * WHEEL_SIZE + slot as usize, timer_id);
+    }
+
+    fn list_push(&mut self, list: usize, timer_id: usize) {
+        let head = self.wheel[list];
+        if head != NIL {
+            self.timers_data[head].prev = timer_id;
+        }
+        let timer = &mut self.timers_data[timer_id];
+        timer.prev = NIL;
+        timer.next = head;
+        timer.list = list;
+        self.wheel[list] = timer_id;
+    }
+
+    fn list_remove(&mut self, timer_id: usize) {
+        let Timer { prev, next, list, .. } = self.timers_data[timer_id];
+        if prev == NIL {
+            self.wheel[list] = next;
+        } else {
+            self.timers_data[prev].next = next;
+        }
+        if next != NIL {
+            self.timers_data[next].prev = prev;
+        }
+        let timer = &mut self.timers_data[timer_id];
+        timer.prev = NIL;
+        timer.next = NIL;
+        timer.list = NIL;
     }
 
-    pub fn set_flag(&mut self, timer_id: usize, flag: TimerState) {
-        self.timers_data[timer_id].flag = flag;
+    //取出整个链表，返回链表的头
+    fn list_take(&mut self, list: usize) -> usize {
+        let head = self.wheel[list];
+        self.wheel[list] = NIL;
+        head
     }
 
-    pub fn init_timer(&mut self, timer_id: usize, fifo_addr: &Fifo, data: u8) {
-        let mut timer = &mut self.timers_data[timer_id];
-        timer.fifo_addr = fifo_addr as *const Fifo as usize;
+    //从时间轮中移除正在运行的定时器，返回是否移除
+    pub fn cancel(&mut self, timer_id: usize) -> bool {
+        if self.timers_data.get(timer_id).map(|t| t.flag) != Some(TimerState::Running) {
+            return false;
+        }
+        self.list_remove(timer_id);
+        self.counting -= 1;
+        self.timers_data[timer_id].flag = TimerState::InUse;
+        true
+    }
+
+    //队列必须是static的，定时器泄漏时也不会留下悬空的指针
+    pub fn init_timer(&mut self, timer_id: usize, fifo: &'static dyn EventSink, data: u32) {
+        let timer = &mut self.timers_data[timer_id];
+        timer.fifo = Some(fifo as *const dyn EventSink);
         timer.data = data;
     }
 
-    pub fn free(&mut self, timer_id: usize) {
-        self.timers_data[timer_id].flag = TimerState::Available;
+    pub fn free(&mut self, timer_id: usize) -> Option<TimerCallback> {
+        self.cancel(timer_id);
+        self.timers_data[timer_id] = Timer::new();
+        self.timers_data[timer_id].next = self.free;
+        self.free = timer_id;
+        //回调在锁外释放
+        self.callbacks[timer_id].take()
+    }
+
+    //释放一个task所属的定时器，没有时返回None
+    pub fn free_owned(&mut self, task: usize) -> Option<Option<TimerCallback>> {
+        let timer_id = self.timers_data.iter().position(|timer| {
+            timer.flag != TimerState::Available && timer.owner == Some(task)
+        })?;
+        Some(self.free(timer_id))
+    }
+
+    //计数加1，把该tick到时的定时器移到EXPIRED_LIST，返回是否有定时器到时
+    //每个tick只处理一个槽，高层的槽每64^L个tick才下移一次
+    fn tick(&mut self) -> bool {
+        if self.counting == 0 {
+            self.count = self.count.wrapping_add(1);
+            return false;
+        }
+        let now = self.count.wrapping_add(1);
+        //低层转完一圈时，把高层对应槽中的定时器重新放到低层
+        let mut level = 1;
+        while level < WHEEL_LEVELS && (now >> (WHEEL_BITS * level as u32 - WHEEL_BITS)) & WHEEL_MASK == 0 {
+            let slot = (now >> (WHEEL_BITS * level as u32)) & WHEEL_MASK;
+            let mut timer_id = self.list_take(level * WHEEL_SIZE + slot as usize);
+            while timer_id != NIL {
+                let next = self.timers_data[timer_id].next;
+                self.wheel_add(timer_id);
+                timer_id = next;
+            }
+            level += 1;
+        }
+        self.count = now;
+        //整个链表移到EXPIRED_LIST，处理期间重新设定的定时器不会再进入该链表
+        let head = self.list_take((now & WHEEL_MASK) as usize);
+        let mut timer_id = head;
+        while timer_id != NIL {
+            self.timers_data[timer_id].list = EXPIRED_LIST;
+            timer_id = self.timers_data[timer_id].next;
+        }
+        self.wheel[EXPIRED_LIST] = head;
+        head != NIL
+    }
+
+    //取出一个本tick到时的定时器，周期定时器会被重新插入
+    fn pop_expired(&mut self) -> Option<usize> {
+        let timer_id = self.wheel[EXPIRED_LIST];
+        if timer_id == NIL {
+            return None;
+        }
+        let timer = self.timers_data[timer_id];
+        self.cancel(timer_id);
+        if let Some(interval) = timer.interval {
+            self.insert(timer_id, timer.timeout.wrapping_add(interval));
+        }
+        Some(timer_id)
     }
 }
 
+//fifo指针只在关中断时通过TIMER_CTL访问
+unsafe impl Send for TimerCtl {}
+
 lazy_static! {
     pub static ref TIMER_CTL: Mutex<TimerCtl> = Mutex::new(TimerCtl::new());
 }
 
+//启动以来的tick数
+pub fn uptime() -> u64 {
+    interrupts::without_interrupts(|| TIMER_CTL.lock().count)
+}
+
+//定时器句柄，drop时取消并释放定时器
+//写入的队列是static的，句柄被mem::forget时定时器也不会比队列活得更久
+//定时器属于创建句柄的任务，任务结束时由free_task释放，句柄不能交给其他任务
+pub struct TimerHandle {
+    id: usize,
+}
+
+impl TimerHandle {
+    //到时时向fifo写入Event::Timer(data)
+    pub fn new(fifo: &'static dyn EventSink, data: u32) -> Option<TimerHandle> {
+        //TASK_CTL要在TIMER_CTL之前获取
+        let owner = task::current();
+        interrupts::without_interrupts(|| {
+            let mut timer_ctl = TIMER_CTL.lock();
+            let id = timer_ctl.alloc().ok()?;
+            timer_ctl.init_timer(id, fifo, data);
+            timer_ctl.timers_data[id].owner = Some(owner);
+            Some(TimerHandle { id })
+        })
+    }
+
+    //一次性定时器，timeout个tick后到时
+    pub fn set_time(&self, timeout: u64) {
+        interrupts::without_interrupts(|| {
+            let mut timer_ctl = TIMER_CTL.lock();
+            timer_ctl.timers_data[self.id].interval = None;
+            timer_ctl.set_time(self.id, timeout);
+        });
+    }
+
+    //周期定时器，每interval个tick到时一次，直到被取消
+    pub fn set_periodic(&self, interval: u64) {
+        let interval = interval.clamp(1, MAX_TIMEOUT);
+        interrupts::without_interrupts(|| {
+            let mut timer_ctl = TIMER_CTL.lock();
+            timer_ctl.timers_data[self.id].interval = Some(interval);
+            timer_ctl.set_time(self.id, interval);
+        });
+    }
+
+    pub fn set_time_ms(&self, ms: u64) {
+        self.set_time(pit::ms_to_ticks(ms));
+    }
+
+    pub fn set_timeout(&self, duration: Duration) {
+        self.set_time(pit::duration_to_ticks(duration));
+    }
+
+    pub fn set_periodic_ms(&self, ms: u64) {
+        self.set_periodic(pit::ms_to_ticks(ms));
+    }
+
+    pub fn set_interval(&self, duration: Duration) {
+        self.set_periodic(pit::duration_to_ticks(duration));
+    }
+
+    //修改到时时写入的data，不影响正在运行的定时器的时间
+    pub fn set_data(&self, data: u32) {
+        interrupts::without_interrupts(|| {
+            TIMER_CTL.lock().timers_data[self.id].data = data;
+        });
+    }
+
+    pub fn cancel(&self) -> bool {
+        interrupts::without_interrupts(|| TIMER_CTL.lock().cancel(self.id))
+    }
+
+    pub fn is_running(&self) -> bool {
+        interrupts::without_interrupts(|| TIMER_CTL.lock().timers_data[self.id].flag == TimerState::Running)
+    }
+}
+
+impl TimerHandle {
+    //到时时在定时器中断中调用callback
+    pub fn with_callback<F: FnMut() + Send + 'static>(callback: F) -> Option<TimerHandle> {
+        let callback: TimerCallback = Box::new(callback);
+        let owner = task::current();
+        interrupts::without_interrupts(|| {
+            let mut timer_ctl = TIMER_CTL.lock();
+            let id = timer_ctl.alloc().ok()?;
+            timer_ctl.callbacks[id] = Some(callback);
+            timer_ctl.timers_data[id].owner = Some(owner);
+            Some(TimerHandle { id })
+        })
+    }
+}
+
+impl Drop for TimerHandle {
+    fn drop(&mut self) {
+        let callback = interrupts::without_interrupts(|| TIMER_CTL.lock().free(self.id));
+        drop(callback);
+    }
+}
+
+//释放已结束的任务的定时器，它栈上的TimerHandle不会再被drop
+pub fn free_task(task: usize) {
+    while let Some(callback) = interrupts::without_interrupts(|| TIMER_CTL.lock().free_owned(task)) {
+        drop(callback);
+    }
+}
+
 pub extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
     //serial_print!(".");
-    interrupts::without_interrupts(|| {
+    let task_switch = interrupts::without_interrupts(|| {
         let mut timer_ctl = TIMER_CTL.lock();
-        timer_ctl.count += 1;
-        if timer_ctl.next > timer_ctl.count {
-            return;
-        }
-        let mut timeout_cnt = 0;
-        for i in 0..timer_ctl.counting {
-            timeout_cnt = i;
-            let timer_id = timer_ctl.timers[i as usize];
+        if !timer_ctl.tick() {
+            return false;
+        }
+        let mut task_switch = false;
+        while let Some(timer_id) = timer_ctl.pop_expired() {
+            if timer_ctl.task_timer == Some(timer_id) {
+                task_switch = true;
+                continue;
+            }
             let timer = timer_ctl.timers_data[timer_id];
-            if timer.timeout > timer_ctl.count {
-                break;
+            if let Some(fifo) = timer.fifo {
+                let fifo = unsafe { &*fifo };
+                let _ = fifo.put_event(Event::Timer(timer.data));
+            }
+            if let Some(callback) = timer_ctl.callbacks[timer_id].as_mut() {
+                callback();
             }
-            timer_ctl.free(timer_id);
-            let fifo = unsafe { &*(timer.fifo_addr as *const Fifo) };
-            fifo.put(timer.data).unwrap();
         }
-        timer_ctl.counting -= timeout_cnt;
-        for i in 0..timer_ctl.counting {
-            timer_ctl.timers[i as usize] = timer_ctl.timers[i as usize + timeout_cnt as usize];
+        task_switch
+    });
+    int::notify_end_of_interrupt(InterruptIndex::Timer);
+    //必须在EOI之后切换，否则切换到的任务将收不到定时器中断
+    if task_switch {
+        task::switch();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::boxed::Box;
+    use alloc::vec::Vec;
+    use crate::asm::io_hlt;
+    use crate::event::Event;
+    use crate::fifo::Fifo;
+    use core::time::Duration;
+    use crate::pit;
+    use super::{time_before, uptime, TimerCtl, TimerHandle, MAX_TIMEOUT};
+
+    static HANDLE_FIFO: Fifo<Event, 8> = Fifo::new();
+
+    //等待定时器中断推进计数
+    fn wait_ticks(ticks: u64) {
+        let start = uptime();
+        while uptime().wrapping_sub(start) < ticks {
+            io_hlt();
         }
-        if timer_ctl.counting > 0 {
-            timer_ctl.next = timer_ctl.timers_data[timer_ctl.timers[0]].timeout;
-        } else {
-            timer_ctl.next = 0xffffffff;
+    }
+
+    //逐个tick推进计数，返回到时的定时器及其到时的时刻
+    fn run_ticks(timer_ctl: &mut TimerCtl, ticks: u64) -> Vec<(usize, u64)> {
+        let mut expired = Vec::new();
+        for _ in 0..ticks {
+            timer_ctl.tick();
+            while let Some(timer_id) = timer_ctl.pop_expired() {
+                expired.push((timer_id, timer_ctl.count));
+            }
         }
-    });
-    unsafe {
-        PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
+        expired
+    }
+
+    #[test_case]
+    fn time_before_across_wraparound() {
+        assert!(time_before(u64::MAX - 1, u64::MAX));
+        assert!(time_before(u64::MAX, 0));
+        assert!(time_before(u64::MAX - 10, 5));
+        assert!(!time_before(5, u64::MAX - 10));
+        assert!(!time_before(7, 7));
+    }
+
+    #[test_case]
+    fn timers_expire_in_order_across_wraparound() {
+        let mut timer_ctl = Box::new(TimerCtl::new());
+        //将计数快进到回绕之前
+        timer_ctl.count = u64::MAX - 5;
+        let late = timer_ctl.alloc().unwrap();
+        let early = timer_ctl.alloc().unwrap();
+        let before_wrap = timer_ctl.alloc().unwrap();
+        timer_ctl.set_time(late, 20);
+        timer_ctl.set_time(early, 8);
+        timer_ctl.set_time(before_wrap, 3);
+
+        let expired = run_ticks(&mut timer_ctl, 30);
+        assert_eq!(expired, [(before_wrap, u64::MAX - 2), (early, 2), (late, 14)]);
+        assert_eq!(timer_ctl.counting, 0);
+    }
+
+    #[test_case]
+    fn periodic_timer_keeps_period_across_wraparound() {
+        let mut timer_ctl = Box::new(TimerCtl::new());
+        timer_ctl.count = u64::MAX - 3;
+        let timer = timer_ctl.alloc().unwrap();
+        timer_ctl.timers_data[timer].interval = Some(4);
+        timer_ctl.set_time(timer, 4);
+
+        let expired = run_ticks(&mut timer_ctl, 12);
+        assert_eq!(expired, [(timer, 0), (timer, 4), (timer, 8)]);
+        assert!(timer_ctl.cancel(timer));
+    }
+
+    #[test_case]
+    fn long_timeouts_cascade_down_the_wheel() {
+        let mut timer_ctl = Box::new(TimerCtl::new());
+        timer_ctl.count = u64::MAX - 100;
+        //分别落在第1、2、3层
+        let timeouts = [100, 5000, 300_000];
+        let mut timers = Vec::new();
+        for &timeout in timeouts.iter() {
+            let timer = timer_ctl.alloc().unwrap();
+            timer_ctl.set_time(timer, timeout);
+            timers.push(timer);
+        }
+        let start = timer_ctl.count;
+        let expired = run_ticks(&mut timer_ctl, 300_001);
+        let expected: Vec<(usize, u64)> = timers.iter().zip(timeouts.iter())
+            .map(|(&timer, &timeout)| (timer, start.wrapping_add(timeout)))
+            .collect();
+        assert_eq!(expired, expected);
+    }
+
+    #[test_case]
+    fn thousands_of_timers_expire_in_order() {
+        let mut timer_ctl = Box::new(TimerCtl::new());
+        timer_ctl.count = u64::MAX - 1000;
+        //线性同余生成伪随机的到时时间
+        let mut seed: u64 = 12345;
+        let mut timeouts = Vec::new();
+        for _ in 0..5000 {
+            seed = seed.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            let timeout = (seed >> 33) % 20000;
+            let timer = timer_ctl.alloc().unwrap();
+            timer_ctl.set_time(timer, timeout);
+            timeouts.push(timeout);
+        }
+        assert_eq!(timer_ctl.counting, 5000);
+        let start = timer_ctl.count;
+        let expired = run_ticks(&mut timer_ctl, 20000);
+        assert_eq!(expired.len(), 5000);
+        for &(timer, at) in expired.iter() {
+            //timeout为0的定时器在下一个tick到时
+            let expected = start.wrapping_add(timeouts[timer].max(1));
+            assert_eq!(at, expected);
+        }
+        assert_eq!(timer_ctl.counting, 0);
+    }
+
+    #[test_case]
+    fn cancelled_and_freed_timers_do_not_fire() {
+        let mut timer_ctl = Box::new(TimerCtl::new());
+        let kept = timer_ctl.alloc().unwrap();
+        let cancelled = timer_ctl.alloc().unwrap();
+        let freed = timer_ctl.alloc().unwrap();
+        for &timer in [kept, cancelled, freed].iter() {
+            timer_ctl.set_time(timer, 70);
+        }
+        assert!(timer_ctl.cancel(cancelled));
+        assert!(!timer_ctl.cancel(cancelled));
+        let _ = timer_ctl.free(freed);
+        //释放的定时器会被重新分配
+        assert_eq!(timer_ctl.alloc(), Ok(freed));
+
+        let expired = run_ticks(&mut timer_ctl, 100);
+        assert_eq!(expired, [(kept, 70)]);
+    }
+
+    #[test_case]
+    fn dropped_handle_never_delivers() {
+        let dropped = TimerHandle::new(&HANDLE_FIFO, 1).unwrap();
+        dropped.set_time(1);
+        drop(dropped);
+        let kept = TimerHandle::new(&HANDLE_FIFO, 2).unwrap();
+        kept.set_time(2);
+        wait_ticks(4);
+        assert_eq!(HANDLE_FIFO.get(), Ok(Event::Timer(2)));
+        assert!(HANDLE_FIFO.get().is_err());
     }
-}
\ No newline at end of file
+
+    #[test_case]
+    fn huge_timeouts_never_fire() {
+        let mut timer_ctl = Box::new(TimerCtl::new());
+        timer_ctl.count = u64::MAX - 10;
+        let max = timer_ctl.alloc().unwrap();
+        let duration = timer_ctl.alloc().unwrap();
+        timer_ctl.set_time(max, u64::MAX);
+        timer_ctl.set_time(duration, pit::duration_to_ticks(Duration::MAX));
+        assert_eq!(pit::duration_to_ticks(Duration::MAX), MAX_TIMEOUT);
+        assert!(run_ticks(&mut timer_ctl, 100).is_empty());
+        assert_eq!(timer_ctl.counting, 2);
+    }
+}
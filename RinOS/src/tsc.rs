@@ -0,0 +1,94 @@
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use core::time::Duration;
+use x86_64::instructions::interrupts;
+use crate::pit;
+use crate::timer;
+
+//校准时等待的PIT时钟数（约50ms）
+const CALIBRATE_COUNT: u16 = 59659;
+
+//TSC的频率（Hz），未校准时为0
+static FREQUENCY: AtomicU64 = AtomicU64::new(0);
+static INVARIANT: AtomicBool = AtomicBool::new(false);
+
+pub fn is_supported() -> bool {
+    let cpuid = unsafe { __cpuid(1) };
+    cpuid.edx & (1 << 4) != 0
+}
+
+//不受频率变化和省电状态影响，以恒定速率计数
+pub fn is_invariant() -> bool {
+    let max_leaf = unsafe { __cpuid(0x8000_0000) }.eax;
+    if max_leaf < 0x8000_0007 {
+        return false;
+    }
+    let cpuid = unsafe { __cpuid(0x8000_0007) };
+    cpuid.edx & (1 << 8) != 0
+}
+
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+//用PIT通道2测量TSC的频率，返回Hz
+pub fn init() -> Result<u64, &'static str> {
+    if !is_supported() {
+        return Err("TSC is not supported");
+    }
+    let (start, end) = interrupts::without_interrupts(|| {
+        let start = rdtsc();
+        pit::busy_wait(CALIBRATE_COUNT);
+        (start, rdtsc())
+    });
+    let hz = (end - start) as u128 * pit::PIT_BASE_FREQUENCY as u128 / CALIBRATE_COUNT as u128;
+    if hz == 0 {
+        return Err("TSC calibration failed");
+    }
+    INVARIANT.store(is_invariant(), Ordering::Relaxed);
+    FREQUENCY.store(hz as u64, Ordering::Release);
+    Ok(hz as u64)
+}
+
+pub fn frequency() -> u64 {
+    FREQUENCY.load(Ordering::Acquire)
+}
+
+//校准时检测到的结果
+pub fn invariant() -> bool {
+    INVARIANT.load(Ordering::Relaxed)
+}
+
+//CPU复位以来的纳秒数，TSC不可用时退回到启动以来定时器的tick
+pub fn now_ns() -> u64 {
+    let hz = frequency();
+    if hz == 0 {
+        let ticks = timer::uptime() as u128;
+        return (ticks * 1_000_000_000 / pit::frequency() as u128) as u64;
+    }
+    (rdtsc() as u128 * 1_000_000_000 / hz as u128) as u64
+}
+
+//单调递增的时刻，用于测量经过的时间
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant {
+    ns: u64,
+}
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant { ns: now_ns() }
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.ns.saturating_sub(earlier.ns))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn as_nanos(&self) -> u64 {
+        self.ns
+    }
+}
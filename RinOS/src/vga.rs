@@ -10,11 +10,15 @@ use x86_64::instructions::interrupts;
 
 #[allow(dead_code)]
 use crate::font;
-use crate::{MOUSE_CURSOR_HEIGHT, MOUSE_CURSOR_WIDTH, serial_print, serial_println};
+use crate::{serial_print, serial_println};
 use crate::layer::LAYERCTL;
+use crate::ansi::{Action, AnsiParser, Attr, Csi};
 
 pub(crate) const SCREEN_WIDTH: usize = 640;
 pub(crate) const SCREEN_HEIGHT: usize = 480;
+//任务栏时间显示区内侧的左上角
+pub const CLOCK_X: usize = SCREEN_WIDTH - 46;
+pub const CLOCK_Y: usize = SCREEN_HEIGHT - 23;
 
 lazy_static! {
     pub static ref VGA: Mutex<Graphics640x480x16> = {
@@ -68,36 +72,56 @@ pub fn init_screen(buf: &mut Vec<Color16>) {
     boxfill(buf, Color16::White, xsize - 3, ysize - 24, xsize - 3, ysize - 3, xsize);
 }
 
-pub fn update_mouse_cursor(bg_index: usize, mouse_layer_index: usize, dx: isize, dy: isize) {
-    interrupts::without_interrupts(|| {
-        LAYERCTL.lock().refresh(bg_index, 32, 0, 32 + 15 * 8 , 16);
-        LAYERCTL.lock().slide_by_diff(mouse_layer_index, dx, dy, MOUSE_CURSOR_WIDTH as isize, MOUSE_CURSOR_HEIGHT as isize);
-    });
-}
-
 //实现写入字符串
 //不能像原书那样实现，报错需要&str的内存分配函数
+//支持ANSI的CSI序列：光标移动（A～D、H）、清除（J、K）和颜色（m）
 pub struct LineWriter {
     init_x: usize,
+    init_y: usize,
     x: usize, //当前列
     y: usize, //当前行
     xsize: usize,
     ysize: usize,
-    color: Color16,
+    attr: Attr,
+    default_attr: Attr,
+    parser: AnsiParser,
+    dirty: Option<(usize, usize, usize, usize)>, //写入过的范围
 }
 
 impl LineWriter {
     pub fn new(color: Color16, x: usize, y: usize, xsize: usize, ysize: usize) -> LineWriter {
         LineWriter {
             init_x: x,
+            init_y: y,
             x,
             y,
             xsize,
             ysize,
-            color
+            attr: Attr::new(color, None),
+            default_attr: Attr::new(color, None),
+            parser: AnsiParser::new(),
+            dirty: None,
         }
     }
 
+    //设定背景色后，写入文字时先填充背景，重画时不需要另外boxfill
+    pub fn set_background(&mut self, bg: Option<Color16>) {
+        self.attr.bg = bg;
+        self.default_attr.bg = bg;
+    }
+
+    //取出上次取出以来写入过的范围(x0, y0, x1, y1)，不含x1和y1
+    pub fn take_dirty(&mut self) -> Option<(usize, usize, usize, usize)> {
+        self.dirty.take()
+    }
+
+    fn mark_dirty(&mut self, x0: usize, y0: usize, x1: usize, y1: usize) {
+        self.dirty = match self.dirty {
+            Some((a0, b0, a1, b1)) => Some((min(a0, x0), min(b0, y0), a1.max(x1), b1.max(y1))),
+            None => Some((x0, y0, x1, y1)),
+        };
+    }
+
     //换行之后x回到起点，y到下一行
     fn new_line(&mut self) {
         self.x = self.init_x;
@@ -106,37 +130,183 @@ impl LineWriter {
 
     pub fn set(&mut self, color: Color16, new_x: usize, new_y: usize) {
         self.init_x = new_x;
+        self.init_y = new_y;
         self.x = new_x;
         self.y = new_y;
-        self.color = color;
+        self.attr = Attr::new(color, self.default_attr.bg);
+        self.default_attr = self.attr;
     }
 
-    pub fn write_str(&mut self, s: &str, buf: &mut Vec<Color16>) {
-        let str_b = s.as_bytes();
+    fn put_char(&mut self, c: u8, buf: &mut Vec<Color16>) {
         let height = self.ysize; //屏幕高度
         let width = self.xsize; //屏幕宽度
-        for i in 0..str_b.len() {
-            if str_b[i] == b'\n' {
+        if self.x + font::FONT_WIDTH > width || self.y + font::FONT_HEIGHT > height {
+            if self.y + font::FONT_HEIGHT * 2 < height {
                 self.new_line();
-                continue;
+            } else {
+                return;
             }
+        }
+        if let Some(bg) = self.attr.bg {
+            boxfill(buf, bg, self.x, self.y, self.x + font::FONT_WIDTH - 1, self.y + font::FONT_HEIGHT - 1, self.xsize);
+        }
+        putfont(buf, self.x, self.y, self.attr.fg(), c as char, self.xsize);
+        self.mark_dirty(self.x, self.y, self.x + font::FONT_WIDTH, self.y + font::FONT_HEIGHT);
 
-            if self.x + font::FONT_WIDTH <= width && self.y + font::FONT_HEIGHT <= height {
-                putfont(buf, self.x, self.y, self.color, str_b[i] as char, self.xsize);
-            } else if self.y + font::FONT_HEIGHT * 2 < height {
-                self.new_line();
-                putfont(buf, self.x, self.y, self.color, str_b[i] as char, self.xsize);
+        //写完之后改变指针位置
+        if self.x + font::FONT_WIDTH < width {
+            self.x += font::FONT_WIDTH;
+        } else if self.y + font::FONT_HEIGHT < height {
+            self.new_line();
+        } else {
+            self.x = width;
+            self.y = height;
+        }
+    }
+
+    //用背景色填充，没有背景色时无法清除
+    fn clear(&mut self, buf: &mut Vec<Color16>, x0: usize, y0: usize, x1: usize, y1: usize) {
+        if let Some(bg) = self.attr.bg {
+            let x1 = min(x1, self.xsize);
+            let y1 = min(y1, self.ysize);
+            if x0 < x1 && y0 < y1 {
+                boxfill(buf, bg, x0, y0, x1 - 1, y1 - 1, self.xsize);
+                self.mark_dirty(x0, y0, x1, y1);
             }
+        }
+    }
 
-            //写完之后改变指针位置
-            if self.x + font::FONT_WIDTH < width {
-                self.x += font::FONT_WIDTH;
-            } else if self.y + font::FONT_HEIGHT < height {
-                self.new_line();
-            } else {
-                self.x = width;
-                self.y = height;
+    fn control(&mut self, c: u8) {
+        match c {
+            b'\n' => self.new_line(),
+            b'\r' => self.x = self.init_x,
+            0x08 => {
+                if self.x >= self.init_x + font::FONT_WIDTH {
+                    self.x -= font::FONT_WIDTH;
+                }
+            }
+            b'\t' => {
+                let tab = font::FONT_WIDTH * 8;
+                self.x = self.init_x + ((self.x - self.init_x) / tab + 1) * tab;
+            }
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, csi: &Csi, buf: &mut Vec<Color16>) {
+        let fw = font::FONT_WIDTH;
+        let fh = font::FONT_HEIGHT;
+        let n = csi.param(0, 1) as usize;
+        match csi.command {
+            b'A' => self.y = self.init_y.max(self.y.saturating_sub(n * fh)),
+            b'B' => self.y = min(self.y + n * fh, self.ysize.saturating_sub(fh)),
+            b'C' => self.x = min(self.x + n * fw, self.xsize.saturating_sub(fw)),
+            b'D' => self.x = self.init_x.max(self.x.saturating_sub(n * fw)),
+            b'H' | b'f' => {
+                self.y = self.init_y + (csi.param(0, 1) as usize - 1) * fh;
+                self.x = self.init_x + (csi.param(1, 1) as usize - 1) * fw;
+            }
+            b'J' => {
+                let (x, y) = (self.x, self.y);
+                match csi.param(0, 0) {
+                    0 => {
+                        self.clear(buf, x, y, self.xsize, y + fh);
+                        self.clear(buf, self.init_x, y + fh, self.xsize, self.ysize);
+                    }
+                    1 => {
+                        self.clear(buf, self.init_x, self.init_y, self.xsize, y);
+                        self.clear(buf, self.init_x, y, x + fw, y + fh);
+                    }
+                    _ => self.clear(buf, self.init_x, self.init_y, self.xsize, self.ysize),
+                }
+            }
+            b'K' => {
+                let (x, y) = (self.x, self.y);
+                match csi.param(0, 0) {
+                    0 => self.clear(buf, x, y, self.xsize, y + fh),
+                    1 => self.clear(buf, self.init_x, y, x + fw, y + fh),
+                    _ => self.clear(buf, self.init_x, y, self.xsize, y + fh),
+                }
             }
+            b'm' => self.attr.apply_sgr(csi, self.default_attr),
+            _ => {}
         }
     }
+
+    pub fn write_str(&mut self, s: &str, buf: &mut Vec<Color16>) {
+        for &b in s.as_bytes() {
+            match self.parser.feed(b) {
+                Some(Action::Print(c)) => self.put_char(c, buf),
+                Some(Action::Control(c)) => self.control(c),
+                Some(Action::Csi(csi)) => self.csi(&csi, buf),
+                None => {}
+            }
+        }
+    }
+}
+
+//写入之后需要刷新到屏幕上的文字输出
+pub trait WindowWrite: fmt::Write {
+    fn flush(&mut self);
+}
+
+//绑定到图层缓冲区的LineWriter，通过window_print!写入时不需要分配内存
+pub struct LayerWriter<'a> {
+    buf: &'a mut Vec<Color16>,
+    layer: usize,
+    writer: LineWriter,
+}
+
+impl<'a> LayerWriter<'a> {
+    pub fn new(buf: &'a mut Vec<Color16>, layer: usize, color: Color16, x: usize, y: usize, xsize: usize, ysize: usize) -> LayerWriter<'a> {
+        LayerWriter {
+            buf,
+            layer,
+            writer: LineWriter::new(color, x, y, xsize, ysize),
+        }
+    }
+
+    pub fn set_background(&mut self, bg: Option<Color16>) {
+        self.writer.set_background(bg);
+    }
+}
+
+impl fmt::Write for LayerWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.writer.write_str(s, self.buf);
+        Ok(())
+    }
+}
+
+impl WindowWrite for LayerWriter<'_> {
+    //只刷新写入过的范围
+    fn flush(&mut self) {
+        if let Some((x0, y0, x1, y1)) = self.writer.take_dirty() {
+            interrupts::without_interrupts(|| {
+                LAYERCTL.lock().refresh(self.layer, x0, y0, x1, y1);
+            });
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn _window_print(writer: &mut dyn WindowWrite, args: fmt::Arguments) {
+    let _ = writer.write_fmt(args);
+    writer.flush();
+}
+
+//向窗口写入格式化的文字并刷新，第一个参数为&mut WindowWrite
+#[macro_export]
+macro_rules! window_print {
+    ($writer:expr, $($arg:tt)*) => {
+        $crate::vga::_window_print($writer, format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! window_println {
+    ($writer:expr) => ($crate::window_print!($writer, "\n"));
+    ($writer:expr, $fmt:expr) => ($crate::window_print!($writer, concat!($fmt, "\n")));
+    ($writer:expr, $fmt:expr, $($arg:tt)*) => ($crate::window_print!(
+        $writer, concat!($fmt, "\n"), $($arg)*));
 }
@@ -1,9 +1,27 @@
 use alloc::vec::Vec;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
 use vga::colors::Color16;
+use x86_64::instructions::interrupts;
 use crate::LineWriter;
+use crate::event::{Event, EventSink};
+use crate::layer::{Layer, LayerCtl, LAYERCTL};
+use crate::mouse::{MousePacket, MOUSE_BUTTONS, MOUSE_LEFT};
 use crate::vga::boxfill;
+use crate::{pit, serial_println, task, timer, SCREEN_HEIGHT, SCREEN_WIDTH};
 
-const CLOSE_BUTTON: [&[u8; 16]; 14] = [
+//标题栏的范围，与make_window中绘制的一致
+pub const TITLE_BAR_TOP: usize = 3;
+pub const TITLE_BAR_BOTTOM: usize = 20;
+//关闭按钮的位置，x从窗口的右端算起
+pub const CLOSE_BUTTON_RIGHT: usize = 21;
+pub const CLOSE_BUTTON_TOP: usize = 5;
+pub const CLOSE_BUTTON_WIDTH: usize = 16;
+pub const CLOSE_BUTTON_HEIGHT: usize = 14;
+//发送关闭请求后等待所属任务关闭窗口的时间
+const CLOSE_TIMEOUT_MS: u64 = 3000;
+
+const CLOSE_BUTTON: [&[u8; CLOSE_BUTTON_WIDTH]; CLOSE_BUTTON_HEIGHT] = [
     b"OOOOOOOOOOOOOOO@",
     b"OQQQQQQQQQQQQQ$@",
     b"OQQQQQQQQQQQQQ$@",
@@ -28,20 +46,301 @@ pub fn make_window(buf: &mut Vec<Color16>, xsize: usize, ysize: usize, caption:
     boxfill(buf, Color16::LightGrey, xsize - 2, 1, xsize - 2, ysize - 2, xsize);
     boxfill(buf, Color16::Black, xsize - 1, 0, xsize - 1, ysize - 1, xsize);
     boxfill(buf, Color16::LightGrey, 2, 2, xsize - 3, ysize - 3, xsize);
-    boxfill(buf, Color16::Blue, 3, 3, xsize - 4, 20, xsize);
+    boxfill(buf, Color16::Blue, 3, TITLE_BAR_TOP, xsize - 4, TITLE_BAR_BOTTOM, xsize);
     boxfill(buf, Color16::DarkGrey, 1, ysize - 2, xsize - 2, ysize - 2, xsize);
     boxfill(buf, Color16::Black, 0, ysize - 1, xsize - 1, ysize - 1, xsize);
     let mut writer = LineWriter::new(Color16::White, 24, 4, xsize, ysize);
     writer.write_str(caption, buf);
 
-    for j in 0..14 as usize {
-        for i in 0..16 as usize {
+    for j in 0..CLOSE_BUTTON_HEIGHT {
+        for i in 0..CLOSE_BUTTON_WIDTH {
+            let p = (j + CLOSE_BUTTON_TOP) * xsize + (xsize - CLOSE_BUTTON_RIGHT + i);
             match CLOSE_BUTTON[j][i] {
-                b'@' => buf[(j + 5) * xsize + (xsize - 21 + i)] = Color16::Black,
-                b'$' => buf[(j + 5) * xsize + (xsize - 21 + i)] = Color16::DarkGrey,
-                b'Q' => buf[(j + 5) * xsize + (xsize - 21 + i)] = Color16::LightGrey,
-                _ => buf[(j + 5) * xsize + (xsize - 21 + i)] = Color16::White,
+                b'@' => buf[p] = Color16::Black,
+                b'$' => buf[p] = Color16::DarkGrey,
+                b'Q' => buf[p] = Color16::LightGrey,
+                _ => buf[p] = Color16::White,
+            }
+        }
+    }
+}
+
+//发给窗口的鼠标按键事件，坐标相对于窗口的左上角
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ButtonEvent {
+    pub button: u8, //MOUSE_LEFT等之一
+    pub pressed: bool,
+    pub x: usize,
+    pub y: usize,
+}
+
+impl ButtonEvent {
+    //(x, y)为光标热点在屏幕上的位置，不在窗口内时返回None
+    pub fn new(window: &Layer, button: u8, pressed: bool, x: usize, y: usize) -> Option<ButtonEvent> {
+        if x < window.x0 || y < window.y0 || x >= window.x0 + window.xsize || y >= window.y0 + window.ysize {
+            return None;
+        }
+        Some(ButtonEvent { button, pressed, x: x - window.x0, y: y - window.y0 })
+    }
+}
+
+//登记的窗口，点击关闭按钮时通知所属任务
+#[derive(Debug, Clone, Copy)]
+pub struct Window {
+    pub layer: usize,
+    pub owner: usize, //所属任务
+    fifo: Option<*const dyn EventSink>, //接收Event::Close的队列，为None时直接结束任务
+    buf: (usize, usize, usize), //图层缓冲区的Vec的指针、长度和容量
+    closing: Option<u64>, //发送关闭请求时的tick
+}
+
+//fifo指针只通过WINDOWS访问，队列是static的
+unsafe impl Send for Window {}
+
+static WINDOWS: Mutex<Vec<Window>> = Mutex::new(Vec::new());
+
+const NO_FOCUS: usize = usize::MAX;
+//焦点窗口的图层，图层号会被重新使用，释放图层后清除
+static FOCUS: AtomicUsize = AtomicUsize::new(NO_FOCUS);
+
+fn clear_focus(layer: usize) {
+    let _ = FOCUS.compare_exchange(layer, NO_FOCUS, Ordering::AcqRel, Ordering::Acquire);
+}
+
+//由当前任务登记窗口，buf是图层的缓冲区
+pub fn register(layer: usize, buf: &Vec<Color16>, fifo: Option<&'static dyn EventSink>) {
+    let window = Window {
+        layer,
+        owner: task::current(),
+        fifo: fifo.map(|fifo| fifo as *const dyn EventSink),
+        buf: (buf.as_ptr() as usize, buf.len(), buf.capacity()),
+        closing: None,
+    };
+    interrupts::without_interrupts(|| WINDOWS.lock().push(window));
+}
+
+//由所属任务调用，取消登记并释放图层，之后由任务释放缓冲区
+pub fn close(layer: usize) {
+    interrupts::without_interrupts(|| {
+        WINDOWS.lock().retain(|window| window.layer != layer);
+        LAYERCTL.lock().free(layer);
+        clear_focus(layer);
+    });
+}
+
+//向窗口所属任务的队列发送事件，没有队列时返回false
+pub fn send(layer: usize, event: Event) -> bool {
+    let windows = WINDOWS.lock();
+    match windows.iter().find(|window| window.layer == layer).and_then(|window| window.fifo) {
+        Some(fifo) => unsafe { &*fifo }.put_event(event).is_ok(),
+        None => false,
+    }
+}
+
+//向所属任务发送Event::Close，重复点击时不再发送
+fn request_close(layer: usize) {
+    let mut windows = WINDOWS.lock();
+    let window = match windows.iter_mut().find(|window| window.layer == layer) {
+        Some(window) => window,
+        None => return,
+    };
+    if window.closing.is_some() {
+        return;
+    }
+    window.closing = Some(timer::uptime());
+    if let Some(fifo) = window.fifo {
+        let fifo = unsafe { &*fifo };
+        let _ = fifo.put_event(Event::Close(layer));
+    }
+}
+
+//结束在时限内没有关闭窗口的任务，它的窗口由task::kill释放
+//需要定期调用，不处理调用者自己的窗口
+pub fn reap() {
+    let timeout = pit::ms_to_ticks(CLOSE_TIMEOUT_MS);
+    let now = timer::uptime();
+    let current = task::current();
+    loop {
+        let owner = interrupts::without_interrupts(|| {
+            WINDOWS.lock().iter().find(|window| {
+                window.owner != current && window.closing.map_or(false, |at| now.wrapping_sub(at) >= timeout)
+            }).map(|window| window.owner)
+        });
+        let owner = match owner {
+            Some(owner) => owner,
+            None => break,
+        };
+        match task::kill(owner) {
+            Ok(()) => serial_println!("[window] killed task {}", owner),
+            Err(e) => {
+                serial_println!("[window] task {}: {}", owner, e);
+                release_task(owner);
+            }
+        }
+    }
+}
+
+//取消登记已结束的任务的所有窗口，并替它释放图层和缓冲区
+//由task在任务号被重新使用之前调用
+pub fn release_task(task: usize) {
+    let windows = interrupts::without_interrupts(|| {
+        let mut windows = WINDOWS.lock();
+        let mut owned = Vec::new();
+        let mut i = 0;
+        while i < windows.len() {
+            if windows[i].owner == task {
+                owned.push(windows.remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        owned
+    });
+    for window in windows {
+        interrupts::without_interrupts(|| LAYERCTL.lock().free(window.layer));
+        clear_focus(window.layer);
+        //原来的Vec在已结束任务的栈上，不会再被drop
+        let (ptr, len, capacity) = window.buf;
+        drop(unsafe { Vec::from_raw_parts(ptr as *mut Color16, len, capacity) });
+    }
+}
+
+//用鼠标操作窗口：点击时移到最上面并获得焦点，拖动标题栏移动窗口
+pub struct WindowCtl {
+    buttons: u8, //上一次的按键状态
+    drag: Option<(usize, usize, usize)>, //拖动中的图层和上一次的光标位置
+}
+
+impl WindowCtl {
+    pub const fn new() -> WindowCtl {
+        WindowCtl { buttons: 0, drag: None }
+    }
+
+    pub fn focus(&self) -> Option<usize> {
+        match FOCUS.load(Ordering::Acquire) {
+            NO_FOCUS => None,
+            layer => Some(layer),
+        }
+    }
+
+    fn set_focus(&mut self, layer: Option<usize>) {
+        FOCUS.store(layer.unwrap_or(NO_FOCUS), Ordering::Release);
+    }
+
+    //光标移动后调用，(x, y)为光标热点的位置
+    pub fn on_mouse(&mut self, layerctl: &mut LayerCtl, mouse_layer: usize, x: usize, y: usize, packet: &MousePacket) {
+        //滚轮交给焦点窗口
+        if packet.scrolled() {
+            if let Some(focus) = self.focus() {
+                send(focus, Event::Wheel(packet.dz));
+            }
+        }
+        for (button, pressed) in self.update_buttons(packet.buttons) {
+            self.on_button(layerctl, mouse_layer, button, pressed, x, y);
+        }
+        if let Some((layer, mx, my)) = self.drag {
+            //拖动中被关闭的窗口
+            if layerctl.layer_data[layer].z.is_none() {
+                self.drag = None;
+            } else if (x, y) != (mx, my) {
+                let window = layerctl.layer_data[layer];
+                let new_x = (window.x0 as isize + x as isize - mx as isize).clamp(0, SCREEN_WIDTH as isize - 1);
+                let new_y = (window.y0 as isize + y as isize - my as isize).clamp(0, SCREEN_HEIGHT as isize - 1);
+                layerctl.slide(layer, new_x as usize, new_y as usize);
+                self.drag = Some((layer, x, y));
+            }
+        }
+    }
+
+    //与上一次相比按下或松开了的按键
+    fn update_buttons(&mut self, buttons: u8) -> impl Iterator<Item = (u8, bool)> {
+        let changed = self.buttons ^ buttons;
+        self.buttons = buttons;
+        MOUSE_BUTTONS.into_iter().filter(move |&button| changed & button != 0).map(move |button| (button, buttons & button != 0))
+    }
+
+    //所有按键都通知光标下的窗口，左键还用于焦点、拖动和关闭
+    fn on_button(&mut self, layerctl: &mut LayerCtl, mouse_layer: usize, button: u8, pressed: bool, x: usize, y: usize) {
+        let left_pressed = button == MOUSE_LEFT && pressed;
+        if button == MOUSE_LEFT && !pressed {
+            self.drag = None;
+        }
+        //背景（高度0）不是窗口
+        let layer = match layerctl.layer_at(x, y, mouse_layer) {
+            Some(layer) if layerctl.layer_data[layer].z.unwrap_or(0) > 0 => layer,
+            _ => {
+                if left_pressed {
+                    self.set_focus(None);
+                }
+                return;
+            }
+        };
+        if let Some(event) = ButtonEvent::new(&layerctl.layer_data[layer], button, pressed, x, y) {
+            send(layer, Event::Button(event));
+        }
+        if !left_pressed {
+            return;
+        }
+        //鼠标图层始终在最上面
+        if let Some(z_max) = layerctl.z_max {
+            layerctl.up_down(layer, Some(z_max - 1));
+        }
+        self.set_focus(Some(layer));
+        let window = layerctl.layer_data[layer];
+        let (wx, wy) = (x - window.x0, y - window.y0);
+        let close_x = window.xsize.saturating_sub(CLOSE_BUTTON_RIGHT);
+        if (close_x..close_x + CLOSE_BUTTON_WIDTH).contains(&wx) && (CLOSE_BUTTON_TOP..CLOSE_BUTTON_TOP + CLOSE_BUTTON_HEIGHT).contains(&wy) {
+            request_close(layer);
+        } else if (3..window.xsize - 3).contains(&wx) && (TITLE_BAR_TOP..=TITLE_BAR_BOTTOM).contains(&wy) {
+            self.drag = Some((layer, x, y));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+    use crate::layer::Layer;
+    use crate::mouse::{MOUSE_LEFT, MOUSE_MIDDLE, MOUSE_RIGHT};
+    use super::{ButtonEvent, WindowCtl};
+
+    fn window_at(x0: usize, y0: usize) -> Layer {
+        let mut layer = Layer::new();
+        layer.x0 = x0;
+        layer.y0 = y0;
+        layer.xsize = 160;
+        layer.ysize = 52;
+        layer
+    }
+
+    #[test_case]
+    fn press_and_release_of_each_button() {
+        for button in [MOUSE_LEFT, MOUSE_RIGHT, MOUSE_MIDDLE] {
+            let mut ctl = WindowCtl::new();
+            assert_eq!(ctl.update_buttons(button).collect::<Vec<_>>(), [(button, true)]);
+            //按住不动时没有变化
+            assert_eq!(ctl.update_buttons(button).count(), 0);
+            assert_eq!(ctl.update_buttons(0).collect::<Vec<_>>(), [(button, false)]);
+        }
+        let mut ctl = WindowCtl::new();
+        ctl.update_buttons(MOUSE_LEFT);
+        let changes: Vec<_> = ctl.update_buttons(MOUSE_RIGHT | MOUSE_MIDDLE).collect();
+        assert_eq!(changes, [(MOUSE_LEFT, false), (MOUSE_RIGHT, true), (MOUSE_MIDDLE, true)]);
+    }
+
+    #[test_case]
+    fn button_event_is_window_relative() {
+        let window = window_at(100, 50);
+        for button in [MOUSE_LEFT, MOUSE_RIGHT, MOUSE_MIDDLE] {
+            for pressed in [true, false] {
+                let event = ButtonEvent::new(&window, button, pressed, 130, 60);
+                assert_eq!(event, Some(ButtonEvent { button, pressed, x: 30, y: 10 }));
             }
+            assert_eq!(ButtonEvent::new(&window, button, true, 100, 50).map(|e| (e.x, e.y)), Some((0, 0)));
+            assert_eq!(ButtonEvent::new(&window, button, true, 259, 101).map(|e| (e.x, e.y)), Some((159, 51)));
+            assert_eq!(ButtonEvent::new(&window, button, true, 99, 60), None);
+            assert_eq!(ButtonEvent::new(&window, button, true, 260, 60), None);
+            assert_eq!(ButtonEvent::new(&window, button, true, 130, 102), None);
         }
     }
-}
\ No newline at end of file
+}